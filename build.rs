@@ -8,4 +8,12 @@ fn main() {
         .file("rust/whisper_wrapper/whisper_wrapper.cpp")
         .compile("whispercpp");
     println!("cargo:rerun-if-changed=rust/src/lib.rs");
+
+    // the tests running a model need whisper itself, built by CMake
+    if std::env::var_os("CARGO_FEATURE_LINK_WHISPER").is_some() {
+        let dir = std::env::var("WHISPER_LIB_DIR").unwrap_or_else(|_| "build".to_string());
+        println!("cargo:rustc-link-search=native={}", dir);
+        println!("cargo:rustc-link-lib=whisper");
+        println!("cargo:rerun-if-env-changed=WHISPER_LIB_DIR");
+    }
 }
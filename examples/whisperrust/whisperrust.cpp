@@ -5,6 +5,15 @@
 #include "lib.rs.h"
 
 int main() {
-    WhisperRust::run_transcript(std::string("output.wav"));
+    WhisperRust::init_default_logging();
+    try {
+        for (const auto & skipped : WhisperRust::run_transcript(std::string("output.wav"))) {
+            fprintf(stderr, "no transcript for %llu ms - %llu ms of channel %zu, inference failed with code %d\n",
+                    (unsigned long long) skipped.start_ms, (unsigned long long) skipped.end_ms, skipped.channel, skipped.code);
+        }
+    } catch (const rust::Error & e) {
+        fprintf(stderr, "transcription failed: %s\n", e.what());
+        return 1;
+    }
     return 0;
-}
\ No newline at end of file
+}
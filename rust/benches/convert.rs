@@ -0,0 +1,53 @@
+//! Sample conversion to f32 with each kernel set the CPU supports, and the dithered
+//! conversion back to 16 bits.
+//!
+//! Run with `cargo bench --bench convert`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use whispercpp::bench_support::{supported_kernels, Kernels};
+use whispercpp::{convert_f32_to_pcm16, Dither};
+
+// a second of 48 kHz audio, the usual rate before resampling
+const LEN: usize = 48000;
+
+fn bench_kernel<S>(c: &mut Criterion, format: &str, data: &[S], kernel: impl Fn(&Kernels) -> fn(&[S], &mut [f32])) {
+    let mut group = c.benchmark_group(format!("convert_{}", format));
+    group.throughput(Throughput::Elements(data.len() as u64));
+    let mut target = vec![0.0f32; data.len()];
+    for kernels in supported_kernels() {
+        let convert = kernel(kernels);
+        group.bench_function(BenchmarkId::from_parameter(kernels.name), |b| {
+            b.iter(|| convert(black_box(data), black_box(&mut target)))
+        });
+    }
+    group.finish();
+}
+
+fn to_f32(c: &mut Criterion) {
+    let pcm16: Vec<i16> = (0..LEN).map(|i| (i * 31) as i16).collect();
+    let pcm32: Vec<i32> = (0..LEN).map(|i| (i as i32).wrapping_mul(1_000_003)).collect();
+    let pcm8: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    let f64s: Vec<f64> = (0..LEN).map(|i| (i as f64 * 0.01).sin()).collect();
+    bench_kernel(c, "pcm16", &pcm16, |k| k.pcm16);
+    bench_kernel(c, "pcm32", &pcm32, |k| k.pcm32);
+    bench_kernel(c, "pcm8", &pcm8, |k| k.pcm8);
+    bench_kernel(c, "f64", &f64s, |k| k.f64);
+}
+
+fn to_pcm16(c: &mut Criterion) {
+    let data: Vec<f32> = (0..LEN).map(|i| (i as f32 * 0.01).sin()).collect();
+    let mut target = vec![0i16; LEN];
+    let mut dither = Dither::default();
+    let mut group = c.benchmark_group("convert_to_pcm16");
+    group.throughput(Throughput::Elements(LEN as u64));
+    group.bench_function("plain", |b| b.iter(|| convert_f32_to_pcm16(black_box(&data), &mut target, None)));
+    group.bench_function("tpdf_dither", |b| {
+        b.iter(|| convert_f32_to_pcm16(black_box(&data), &mut target, Some(&mut dither)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, to_f32, to_pcm16);
+criterion_main!(benches);
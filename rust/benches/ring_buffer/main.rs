@@ -0,0 +1,231 @@
+//! Throughput and latency of the lock-free ring buffer against the mutex based one it replaced,
+//! and fan-out to several consumers through one SPMC buffer against a copy per consumer.
+//!
+//! Run with `cargo bench --bench ring_buffer`.
+
+mod mutex_rb;
+
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use whispercpp::bench_support::{Consumer, Producer, RbConsumer, RbError, RbProducer, SpmcRb, SpscRb, RB};
+
+use crate::mutex_rb::{MutexConsumer, MutexProducer, MutexRb, SampleRange};
+
+// a minute of 16 kHz audio per iteration
+const TOTAL: usize = 16000 * 60;
+// 10 ms, the block of a live input or capture device
+const LATENCY_BLOCK: usize = 160;
+// VAD, language detection and transcription
+const FAN_OUT: usize = 3;
+
+type NewRb<P, C> = fn(usize) -> (P, C);
+
+/// Writing side of either ring buffer.
+trait BenchProducer: Send + 'static {
+    fn write(&self, data: &[i16]);
+}
+
+/// Reading side of either ring buffer, used as the inference thread does with its chunks.
+trait BenchConsumer: Send + 'static {
+    /// Waits for `len` samples from `pos` on, or the end of the stream, and reads them.
+    /// Returns how many were read.
+    fn read(&mut self, pos: usize, len: usize, scratch: &mut [f32]) -> usize;
+    fn commit(&mut self, pos: usize);
+}
+
+impl BenchProducer for Producer {
+    fn write(&self, data: &[i16]) {
+        self.write_ext_blocking(data).unwrap();
+    }
+}
+
+impl BenchConsumer for Consumer {
+    fn read(&mut self, pos: usize, len: usize, _scratch: &mut [f32]) -> usize {
+        match self.peek_blocking(pos, len) {
+            Ok(view) => {
+                black_box(view.as_slices());
+                view.len()
+            }
+            Err(RbError::EOF) => 0,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn commit(&mut self, pos: usize) {
+        self.commit_read(pos).unwrap();
+    }
+}
+
+impl BenchProducer for MutexProducer {
+    fn write(&self, data: &[i16]) {
+        self.write_ext_blocking(data).unwrap();
+    }
+}
+
+impl BenchConsumer for MutexConsumer {
+    fn read(&mut self, pos: usize, len: usize, scratch: &mut [f32]) -> usize {
+        let range = match self.peek_blocking(pos, &mut scratch[..len]) {
+            Ok(range) => range,
+            Err(mutex_rb::RbError::Eof(range)) => range,
+            Err(e) => panic!("{:?}", e),
+        };
+        match range {
+            SampleRange::Adjacent(ptr, n) => {
+                black_box(unsafe { std::slice::from_raw_parts(ptr, n) });
+                n
+            }
+            SampleRange::NonAdjacent(n) => {
+                black_box(&scratch[..n]);
+                n
+            }
+            SampleRange::EofEmpty => 0,
+        }
+    }
+
+    fn commit(&mut self, pos: usize) {
+        self.commit_read(pos);
+    }
+}
+fn lock_free(capacity: usize) -> (Producer, Consumer) {
+    let rb = SpscRb::new(capacity);
+    (rb.producer(), rb.consumer())
+}
+
+fn mutex(capacity: usize) -> (MutexProducer, MutexConsumer) {
+    let rb = MutexRb::new(capacity);
+    (rb.producer(), rb.consumer())
+}
+
+// moves `TOTAL` samples through a new buffer, the producer on its own thread
+fn transfer<P: BenchProducer, C: BenchConsumer>(new: NewRb<P, C>, capacity: usize, write_block: usize,
+                                                read_block: usize) -> Duration {
+    let (prod, mut cons) = new(capacity);
+    let data = vec![1000i16; write_block];
+    let start = Instant::now();
+    let writer = thread::spawn(move || {
+        let mut written = 0;
+        while written < TOTAL {
+            let len = write_block.min(TOTAL - written);
+            prod.write(&data[..len]);
+            written += len;
+        }
+    });
+    let mut buf = vec![0.0f32; read_block];
+    let mut pos = 0;
+    while pos < TOTAL {
+        let len = read_block.min(TOTAL - pos);
+        pos += cons.read(pos, len, &mut buf);
+        cons.commit(pos);
+    }
+    let elapsed = start.elapsed();
+    writer.join().unwrap();
+    elapsed
+}
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rb_throughput");
+    group.throughput(Throughput::Elements(TOTAL as u64));
+    group.sample_size(20);
+    // (capacity, write block, read block): the transcription setup, then a small buffer
+    // where producer and consumer wait on each other all the time
+    for (capacity, write_block, read_block) in [(16000 * 120, 1024, 16000), (4096, 256, 1024)] {
+        let params = format!("cap{}_w{}_r{}", capacity, write_block, read_block);
+        group.bench_with_input(BenchmarkId::new("lock_free", &params), &(), |b, _| {
+            b.iter_custom(|iters| (0..iters).map(|_| transfer(lock_free, capacity, write_block, read_block)).sum())
+        });
+        group.bench_with_input(BenchmarkId::new("mutex", &params), &(), |b, _| {
+            b.iter_custom(|iters| (0..iters).map(|_| transfer(mutex, capacity, write_block, read_block)).sum())
+        });
+    }
+    group.finish();
+}
+
+// round trips of a 10 ms block: written here, echoed back by another thread through a second buffer
+fn round_trips<P: BenchProducer, C: BenchConsumer>(new: NewRb<P, C>, iters: u64) -> Duration {
+    let (to_echo, mut from_main) = new(LATENCY_BLOCK * 4);
+    let (to_main, mut from_echo) = new(LATENCY_BLOCK * 4);
+    let echo = thread::spawn(move || {
+        let mut buf = vec![0.0f32; LATENCY_BLOCK];
+        let block = vec![1000i16; LATENCY_BLOCK];
+        let mut pos = 0;
+        for _ in 0..iters {
+            pos += from_main.read(pos, LATENCY_BLOCK, &mut buf);
+            from_main.commit(pos);
+            to_main.write(&block);
+        }
+    });
+    let mut buf = vec![0.0f32; LATENCY_BLOCK];
+    let block = vec![1000i16; LATENCY_BLOCK];
+    let mut pos = 0;
+    let start = Instant::now();
+    for _ in 0..iters {
+        to_echo.write(&block);
+        pos += from_echo.read(pos, LATENCY_BLOCK, &mut buf);
+        from_echo.commit(pos);
+    }
+    let elapsed = start.elapsed();
+    echo.join().unwrap();
+    elapsed
+}
+
+fn latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rb_round_trip_10ms_block");
+    group.bench_function("lock_free", |b| b.iter_custom(|iters| round_trips(lock_free, iters)));
+    group.bench_function("mutex", |b| b.iter_custom(|iters| round_trips(mutex, iters)));
+    group.finish();
+}
+
+// moves `TOTAL` samples from the producer to `FAN_OUT` consumers, each on its own thread
+fn fan_out(shared: bool, capacity: usize, block: usize) -> Duration {
+    let (producers, consumers): (Vec<Producer>, Vec<Consumer>) = if shared {
+        let rb = SpmcRb::new(capacity, FAN_OUT);
+        (vec![rb.producer()], (0..FAN_OUT).map(|_| rb.consumer()).collect())
+    } else {
+        (0..FAN_OUT).map(|_| lock_free(capacity)).unzip()
+    };
+    let data = vec![1000i16; block];
+    let start = Instant::now();
+    let readers: Vec<_> = consumers.into_iter()
+        .map(|mut cons| thread::spawn(move || {
+            let mut pos = 0;
+            while pos < TOTAL {
+                pos += cons.read(pos, block.min(TOTAL - pos), &mut []);
+                cons.commit(pos);
+            }
+        }))
+        .collect();
+    let mut written = 0;
+    while written < TOTAL {
+        let len = block.min(TOTAL - written);
+        for prod in &producers {
+            prod.write_ext_blocking(&data[..len]).unwrap();
+        }
+        written += len;
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn fan_out_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rb_fan_out");
+    group.throughput(Throughput::Elements(TOTAL as u64));
+    group.sample_size(20);
+    for (capacity, block) in [(16000 * 120, 1024), (4096, 256)] {
+        let params = format!("cap{}_b{}", capacity, block);
+        group.bench_with_input(BenchmarkId::new("spmc", &params), &(), |b, _| {
+            b.iter_custom(|iters| (0..iters).map(|_| fan_out(true, capacity, block)).sum())
+        });
+        group.bench_with_input(BenchmarkId::new("spsc_per_consumer", &params), &(), |b, _| {
+            b.iter_custom(|iters| (0..iters).map(|_| fan_out(false, capacity, block)).sum())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput, latency, fan_out_throughput);
+criterion_main!(benches);
@@ -0,0 +1,294 @@
+//! The ring buffer as it was before the lock-free rewrite, kept as the baseline of the
+//! benchmarks: every access locks the whole buffer, blocking waits on condvars.
+
+use std::cmp;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use whispercpp::bench_support::convert_pcm16_to_f32;
+
+#[derive(Debug)]
+pub enum RbError {
+    TimedOut,
+    Again,
+    Eof(SampleRange),
+}
+
+pub type Result<T> = std::result::Result<T, RbError>;
+
+#[derive(Debug)]
+pub enum SampleRange {
+    Adjacent(*const f32, usize),
+    NonAdjacent(usize),
+    EofEmpty,
+}
+
+struct Inspector {
+    gpos: Arc<AtomicUsize>,
+    read_pos: Arc<AtomicUsize>,
+    write_pos: Arc<AtomicUsize>,
+    size: usize,
+    closed: Arc<AtomicBool>,
+}
+
+/// Mutex and condvar based SPSC ring buffer.
+pub struct MutexRb {
+    buf: Arc<Mutex<Vec<f32>>>,
+    inspector: Arc<Inspector>,
+    slots_free: Arc<Condvar>,
+    data_available: Arc<Condvar>,
+}
+
+impl MutexRb {
+    pub fn new(size: usize) -> Self {
+        let (read_pos, write_pos) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        MutexRb {
+            buf: Arc::new(Mutex::new(vec![f32::default(); size + 1])),
+            slots_free: Arc::new(Condvar::new()),
+            data_available: Arc::new(Condvar::new()),
+            // the additional element is used to distinct between empty and full state
+            inspector: Arc::new(Inspector {
+                gpos: Arc::new(AtomicUsize::new(0)),
+                read_pos,
+                write_pos,
+                size: size + 1,
+                closed: Arc::new(AtomicBool::new(false)),
+            }),
+        }
+    }
+
+    pub fn producer(&self) -> MutexProducer {
+        MutexProducer {
+            buf: self.buf.clone(),
+            inspector: self.inspector.clone(),
+            slots_free: self.slots_free.clone(),
+            data_available: self.data_available.clone(),
+        }
+    }
+
+    pub fn consumer(&self) -> MutexConsumer {
+        MutexConsumer {
+            buf: self.buf.clone(),
+            inspector: self.inspector.clone(),
+            slots_free: self.slots_free.clone(),
+            data_available: self.data_available.clone(),
+        }
+    }
+}
+
+impl Inspector {
+    #[inline(always)]
+    fn is_full(&self) -> bool {
+        self.slots_free() == 0
+    }
+
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.size - 1
+    }
+
+    #[inline(always)]
+    fn slots_free(&self) -> usize {
+        let wr_pos = self.write_pos.load(Ordering::Relaxed);
+        let re_pos = self.read_pos.load(Ordering::Relaxed);
+        if wr_pos < re_pos {
+            re_pos - wr_pos - 1
+        } else {
+            self.capacity() - wr_pos + re_pos
+        }
+    }
+
+    #[inline(always)]
+    fn count(&self) -> usize {
+        self.capacity() - self.slots_free()
+    }
+
+    #[inline(always)]
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Producer view into the ring buffer.
+pub struct MutexProducer {
+    buf: Arc<Mutex<Vec<f32>>>,
+    inspector: Arc<Inspector>,
+    slots_free: Arc<Condvar>,
+    data_available: Arc<Condvar>,
+}
+
+impl Drop for MutexProducer {
+    /// A dropped producer can't write anymore, let the consumer see EOF.
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Consumer view into the ring buffer.
+pub struct MutexConsumer {
+    buf: Arc<Mutex<Vec<f32>>>,
+    inspector: Arc<Inspector>,
+    slots_free: Arc<Condvar>,
+    data_available: Arc<Condvar>,
+}
+
+impl Drop for MutexConsumer {
+    /// Nobody reads anymore, wake up and fail a producer waiting for free slots.
+    fn drop(&mut self) {
+        let _guard = self.buf.lock().unwrap();
+        self.inspector.close();
+        self.slots_free.notify_one();
+    }
+}
+
+impl MutexProducer {
+    pub fn write_blocking(&self, data: &[i16]) -> Result<Option<usize>> {
+        self.write_blocking_timeout(data, Duration::MAX)
+    }
+
+    pub fn write_blocking_timeout(&self, data: &[i16], timeout: Duration) -> Result<Option<usize>> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let guard = self.buf.lock().unwrap();
+        if self.inspector.is_closed() {
+            return Err(RbError::Eof(SampleRange::EofEmpty));
+        }
+        let mut buf = if self.inspector.is_full() {
+            if timeout == Duration::MAX {
+                // No need to call wait_timeout if the duration is max
+                self.slots_free.wait(guard).unwrap()
+            } else {
+                let (guard, result) = self.slots_free.wait_timeout(guard, timeout).unwrap();
+                if result.timed_out() {
+                    return Err(RbError::TimedOut);
+                }
+                guard
+            }
+        } else {
+            guard
+        };
+        if self.inspector.is_closed() {
+            return Err(RbError::Eof(SampleRange::EofEmpty));
+        }
+
+        let buf_len = buf.len();
+        let data_len = data.len();
+        let wr_pos = self.inspector.write_pos.load(Ordering::Relaxed);
+        let cnt = cmp::min(data_len, self.inspector.slots_free());
+
+        if (wr_pos + cnt) < buf_len {
+            convert_pcm16_to_f32(&data[..cnt], &mut buf[wr_pos..wr_pos + cnt]);
+        } else {
+            let d = buf_len - wr_pos;
+            convert_pcm16_to_f32(&data[..d], &mut buf[wr_pos..]);
+            convert_pcm16_to_f32(&data[d..cnt], &mut buf[..(cnt-d)]);
+        }
+        self.inspector
+            .write_pos
+            .store((wr_pos + cnt) % buf_len, Ordering::Relaxed);
+
+        self.data_available.notify_one();
+        Ok(Some(cnt))
+    }
+
+    pub fn write_ext_blocking(&self, data: &[i16]) -> Result<()> {
+        let buf_len = data.len();
+        let mut pos = 0usize;
+        while let Some(written) = self.write_blocking(&data[pos..])? {
+            pos += written;
+            if pos == buf_len {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        let _guard = self.buf.lock().unwrap();
+        self.inspector.close();
+        self.data_available.notify_one();
+    }
+}
+
+impl MutexConsumer {
+
+    pub fn peek_ext(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange> {
+        let guard = self.buf.lock().unwrap();
+        let gpos = self.inspector.gpos.load(Ordering::Relaxed);
+        if gpos > pos {
+            panic!("can't read data already read committed")
+        }
+        let re_pos_offset = pos - gpos;
+        let available_cnt = self.inspector.count() - re_pos_offset;
+        let mut req_cnt = data.len();
+        let mut is_tail_partial = false;
+        if available_cnt < req_cnt {
+            if self.inspector.is_closed() {
+                is_tail_partial = true;
+                if available_cnt == 0 {
+                    return Err(RbError::Eof(SampleRange::EofEmpty));
+                }
+                req_cnt = available_cnt;
+            } else {
+                return Err(RbError::Again)
+            }
+        }
+        let buf = guard.as_slice();
+        let buf_len = buf.len();
+        let re_pos = (self.inspector.read_pos.load(Ordering::Relaxed) + re_pos_offset) % buf_len;
+        if (re_pos + req_cnt) < buf_len {
+            // if sample range is adjacent to buffer, return a pointer to the buffer
+            if !is_tail_partial {
+                Ok(SampleRange::Adjacent(buf[re_pos..re_pos + req_cnt].as_ptr(), req_cnt))
+            } else {
+                Err(RbError::Eof(SampleRange::Adjacent(buf[re_pos..re_pos + req_cnt].as_ptr(), req_cnt)))
+            }
+        } else {
+            let d = buf_len - re_pos;
+            data[..d].copy_from_slice(&buf[re_pos..]);
+            data[d..].copy_from_slice(&buf[..(req_cnt - d)]);
+            if !is_tail_partial {
+                Ok(SampleRange::NonAdjacent(req_cnt))
+            } else {
+                Err(RbError::Eof(SampleRange::NonAdjacent(req_cnt)))
+            }
+        }
+    }
+
+    pub fn peek_blocking(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange> {
+        loop {
+            match self.peek_ext(pos, data) {
+                Ok(sr) => return Ok(sr),
+                Err(RbError::Again) => continue,
+                Err(RbError::Eof(sr)) => return Err(RbError::Eof(sr)),
+                _ => (),
+            }
+            let guard = self.buf.lock().unwrap();
+            let _buf = self.data_available.wait(guard).unwrap();
+        }
+    }
+
+    pub fn commit_read(&self, read_end: usize) {
+        let guard = self.buf.lock().unwrap();
+        let buf_len = guard.as_slice().len();
+        let gpos = self.inspector.gpos.load(Ordering::Relaxed);
+        let cnt = read_end - gpos;
+        let available_cnt = self.inspector.count();
+        if available_cnt < cnt {
+            panic!("can't commit data, not enough")
+        }
+        self.inspector.gpos.store(read_end, Ordering::Relaxed);
+        let re_pos = self.inspector.read_pos.load(Ordering::Relaxed);
+        self.inspector.read_pos.store((re_pos + cnt) % buf_len, Ordering::Relaxed);
+        self.slots_free.notify_one();
+    }
+
+}
@@ -1,38 +1,453 @@
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::{
+    vcvt_f32_f64, vcvt_high_f32_f64, vcvtq_f32_s32, vcvtq_f32_u32, vdupq_n_f32, vget_low_s16, vget_low_u16,
+    vld1_u8, vld1q_f64, vld1q_s16, vld1q_s32, vmovl_high_s16, vmovl_high_u16, vmovl_s16, vmovl_u16, vmovl_u8,
+    vmulq_f32, vst1q_f32, vsubq_f32,
+};
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::{
-    __m128i, _mm_loadu_si128,
-    __m256, __m256i, _mm256_cvtepi16_epi32,
-    _mm256_cvtepi32_ps, _mm256_mul_ps, _mm256_set1_ps,
+    __m128i, __m256i, _mm256_add_ps, _mm256_castps128_ps256, _mm256_cvtepi16_epi32, _mm256_cvtepi32_ps,
+    _mm256_cvtepu8_epi32, _mm256_cvtpd_ps, _mm256_insertf128_ps, _mm256_loadu_pd, _mm256_loadu_si256,
+    _mm256_mul_ps, _mm256_set1_ps, _mm256_storeu_ps, _mm_loadl_epi64, _mm_loadu_si128,
+};
+// stable since Rust 1.89, hence the feature
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+use std::arch::x86_64::{
+    _mm512_add_ps, _mm512_cvtepi16_epi32, _mm512_cvtepi32_ps, _mm512_cvtepu8_epi32, _mm512_cvtpd_ps,
+    _mm512_loadu_epi32, _mm512_loadu_pd, _mm512_mul_ps, _mm512_set1_ps, _mm512_storeu_ps,
 };
 
-#[cfg(target_arch = "x86_64")]
-fn simd_convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
-    let scale = 32768.0f32;
-    let step_cnt =  data.len() / 8;
-    for i in (0..step_cnt) {
-        unsafe {
-            let x: __m128i = _mm_loadu_si128(data[i*8..i*8+8].as_ptr() as *const __m128i);
-            let y: __m256i = _mm256_cvtepi16_epi32(x); // convert to i32
-            let z: __m256 = _mm256_cvtepi32_ps(y); // convert to f32
-            let scale: __m256 = _mm256_set1_ps(1.0f32/ scale);
-            let w: __m256 = _mm256_mul_ps(z, scale); // divide by scale
-            let w_arr: [f32; 8] = std::mem::transmute(w);
-            target[i*8..i*8+8].copy_from_slice(&w_arr[0..8]);
-        }
+use once_cell::sync::Lazy;
+
+/// A sample type the ring buffer takes, converted to f32 in [-1.0, 1.0] when written.
+pub trait Sample: Copy + Send + Sync + 'static {
+    /// Converts `data` into `target`, which is as long as `data`.
+    fn convert_to_f32(data: &[Self], target: &mut [f32]);
+}
+
+impl Sample for i16 {
+    fn convert_to_f32(data: &[i16], target: &mut [f32]) {
+        (kernels().pcm16)(data, target);
     }
-    for i in (step_cnt*8..data.len()) {
-        target[i] = data[i] as f32 / scale;
+}
+
+impl Sample for i32 {
+    fn convert_to_f32(data: &[i32], target: &mut [f32]) {
+        (kernels().pcm32)(data, target);
     }
 }
 
-pub fn convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
+impl Sample for u8 {
+    fn convert_to_f32(data: &[u8], target: &mut [f32]) {
+        (kernels().pcm8)(data, target);
+    }
+}
+
+impl Sample for f32 {
+    fn convert_to_f32(data: &[f32], target: &mut [f32]) {
+        target.copy_from_slice(data);
+    }
+}
+
+impl Sample for f64 {
+    fn convert_to_f32(data: &[f64], target: &mut [f32]) {
+        (kernels().f64)(data, target);
+    }
+}
+
+/// The conversions to f32 of one instruction set, all giving the same results as the scalar ones.
+pub struct Kernels {
+    pub name: &'static str,
+    pub pcm16: fn(&[i16], &mut [f32]),
+    pub pcm32: fn(&[i32], &mut [f32]),
+    pub pcm8: fn(&[u8], &mut [f32]),
+    pub f64: fn(&[f64], &mut [f32]),
+}
+
+static SCALAR: Kernels = Kernels {
+    name: "scalar",
+    pcm16: scalar_convert_pcm16_to_f32,
+    pcm32: scalar_convert_pcm32_to_f32,
+    pcm8: scalar_convert_pcm8_to_f32,
+    f64: scalar_convert_f64_to_f32,
+};
+
+// the SIMD kernels are only handed out by `supported_kernels`, after checking the CPU has them
+#[cfg(target_arch = "x86_64")]
+static AVX2: Kernels = Kernels {
+    name: "avx2",
+    pcm16: |data, target| unsafe { avx2_convert_pcm16_to_f32(data, target) },
+    pcm32: |data, target| unsafe { avx2_convert_pcm32_to_f32(data, target) },
+    pcm8: |data, target| unsafe { avx2_convert_pcm8_to_f32(data, target) },
+    f64: |data, target| unsafe { avx2_convert_f64_to_f32(data, target) },
+};
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+static AVX512: Kernels = Kernels {
+    name: "avx512",
+    pcm16: |data, target| unsafe { avx512_convert_pcm16_to_f32(data, target) },
+    pcm32: |data, target| unsafe { avx512_convert_pcm32_to_f32(data, target) },
+    pcm8: |data, target| unsafe { avx512_convert_pcm8_to_f32(data, target) },
+    f64: |data, target| unsafe { avx512_convert_f64_to_f32(data, target) },
+};
+
+#[cfg(target_arch = "aarch64")]
+static NEON: Kernels = Kernels {
+    name: "neon",
+    pcm16: |data, target| unsafe { neon_convert_pcm16_to_f32(data, target) },
+    pcm32: |data, target| unsafe { neon_convert_pcm32_to_f32(data, target) },
+    pcm8: |data, target| unsafe { neon_convert_pcm8_to_f32(data, target) },
+    f64: |data, target| unsafe { neon_convert_f64_to_f32(data, target) },
+};
+
+/// The kernels the CPU can run, the fastest last.
+pub fn supported_kernels() -> Vec<&'static Kernels> {
+    #[allow(unused_mut)]
+    let mut supported = vec![&SCALAR];
     #[cfg(target_arch = "x86_64")]
     {
         if is_x86_feature_detected!("avx2") {
-            return simd_convert_pcm16_to_f32(data, target);
+            supported.push(&AVX2);
+        }
+        #[cfg(feature = "avx512")]
+        if is_x86_feature_detected!("avx512f") {
+            supported.push(&AVX512);
         }
     }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            supported.push(&NEON);
+        }
+    }
+    supported
+}
+
+// picked once by `init_kernels`, instead of checking the CPU on every call
+static KERNELS: Lazy<&'static Kernels> = Lazy::new(|| {
+    let kernels = *supported_kernels().last().unwrap();
+    log::debug!("sample conversion uses the {} kernels", kernels.name);
+    kernels
+});
+
+/// Picks the kernels, so that CPU detection runs while the pipeline is set up
+/// rather than in the first conversion.
+pub(crate) fn init_kernels() {
+    Lazy::force(&KERNELS);
+}
+
+/// The fastest kernels the CPU can run.
+pub fn kernels() -> &'static Kernels {
+    &KERNELS
+}
+
+fn scalar_convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
     for i in 0..data.len() {
         target[i] = data[i] as f32 / 32768.0;
     }
 }
+
+fn scalar_convert_pcm32_to_f32(data: &[i32], target: &mut [f32]) {
+    for i in 0..data.len() {
+        target[i] = data[i] as f32 / 2147483648.0;
+    }
+}
+
+fn scalar_convert_pcm8_to_f32(data: &[u8], target: &mut [f32]) {
+    for i in 0..data.len() {
+        target[i] = (data[i] as f32 - 128.0) / 128.0;
+    }
+}
+
+fn scalar_convert_f64_to_f32(data: &[f64], target: &mut [f32]) {
+    for i in 0..data.len() {
+        target[i] = data[i] as f32;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
+    let scale = 32768.0f32;
+    let step_cnt =  data.len() / 8;
+    for i in 0..step_cnt {
+        let x: __m128i = _mm_loadu_si128(data[i*8..i*8+8].as_ptr() as *const __m128i);
+        let y: __m256i = _mm256_cvtepi16_epi32(x); // convert to i32
+        let z = _mm256_cvtepi32_ps(y); // convert to f32
+        let w = _mm256_mul_ps(z, _mm256_set1_ps(1.0f32 / scale)); // divide by scale
+        _mm256_storeu_ps(target[i*8..i*8+8].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm16_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_convert_pcm32_to_f32(data: &[i32], target: &mut [f32]) {
+    let scale = 2147483648.0f32;
+    let step_cnt = data.len() / 8;
+    for i in 0..step_cnt {
+        let x = _mm256_loadu_si256(data[i*8..i*8+8].as_ptr() as *const __m256i);
+        let w = _mm256_mul_ps(_mm256_cvtepi32_ps(x), _mm256_set1_ps(1.0f32 / scale));
+        _mm256_storeu_ps(target[i*8..i*8+8].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm32_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_convert_pcm8_to_f32(data: &[u8], target: &mut [f32]) {
+    let step_cnt = data.len() / 8;
+    for i in 0..step_cnt {
+        // 8 bytes into the low half, widened to i32
+        let x = _mm256_cvtepu8_epi32(_mm_loadl_epi64(data[i*8..i*8+8].as_ptr() as *const __m128i));
+        let z = _mm256_add_ps(_mm256_cvtepi32_ps(x), _mm256_set1_ps(-128.0));
+        let w = _mm256_mul_ps(z, _mm256_set1_ps(1.0f32 / 128.0));
+        _mm256_storeu_ps(target[i*8..i*8+8].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm8_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_convert_f64_to_f32(data: &[f64], target: &mut [f32]) {
+    let step_cnt = data.len() / 8;
+    for i in 0..step_cnt {
+        let low = _mm256_cvtpd_ps(_mm256_loadu_pd(data[i*8..i*8+4].as_ptr()));
+        let high = _mm256_cvtpd_ps(_mm256_loadu_pd(data[i*8+4..i*8+8].as_ptr()));
+        let w = _mm256_insertf128_ps::<1>(_mm256_castps128_ps256(low), high);
+        _mm256_storeu_ps(target[i*8..i*8+8].as_mut_ptr(), w);
+    }
+    scalar_convert_f64_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn avx512_convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
+    let step_cnt = data.len() / 16;
+    for i in 0..step_cnt {
+        let x = _mm512_cvtepi16_epi32(_mm256_loadu_si256(data[i*16..i*16+16].as_ptr() as *const __m256i));
+        let w = _mm512_mul_ps(_mm512_cvtepi32_ps(x), _mm512_set1_ps(1.0f32 / 32768.0));
+        _mm512_storeu_ps(target[i*16..i*16+16].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm16_to_f32(&data[step_cnt*16..], &mut target[step_cnt*16..]);
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn avx512_convert_pcm32_to_f32(data: &[i32], target: &mut [f32]) {
+    let step_cnt = data.len() / 16;
+    for i in 0..step_cnt {
+        let x = _mm512_loadu_epi32(data[i*16..i*16+16].as_ptr());
+        let w = _mm512_mul_ps(_mm512_cvtepi32_ps(x), _mm512_set1_ps(1.0f32 / 2147483648.0));
+        _mm512_storeu_ps(target[i*16..i*16+16].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm32_to_f32(&data[step_cnt*16..], &mut target[step_cnt*16..]);
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn avx512_convert_pcm8_to_f32(data: &[u8], target: &mut [f32]) {
+    let step_cnt = data.len() / 16;
+    for i in 0..step_cnt {
+        let x = _mm512_cvtepu8_epi32(_mm_loadu_si128(data[i*16..i*16+16].as_ptr() as *const __m128i));
+        let z = _mm512_add_ps(_mm512_cvtepi32_ps(x), _mm512_set1_ps(-128.0));
+        let w = _mm512_mul_ps(z, _mm512_set1_ps(1.0f32 / 128.0));
+        _mm512_storeu_ps(target[i*16..i*16+16].as_mut_ptr(), w);
+    }
+    scalar_convert_pcm8_to_f32(&data[step_cnt*16..], &mut target[step_cnt*16..]);
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn avx512_convert_f64_to_f32(data: &[f64], target: &mut [f32]) {
+    let step_cnt = data.len() / 16;
+    for i in 0..step_cnt {
+        // 8 doubles narrow to 8 floats, two of them fill a vector
+        let low = _mm512_cvtpd_ps(_mm512_loadu_pd(data[i*16..i*16+8].as_ptr()));
+        let high = _mm512_cvtpd_ps(_mm512_loadu_pd(data[i*16+8..i*16+16].as_ptr()));
+        _mm256_storeu_ps(target[i*16..i*16+8].as_mut_ptr(), low);
+        _mm256_storeu_ps(target[i*16+8..i*16+16].as_mut_ptr(), high);
+    }
+    scalar_convert_f64_to_f32(&data[step_cnt*16..], &mut target[step_cnt*16..]);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn neon_convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
+    let scale = vdupq_n_f32(1.0f32 / 32768.0);
+    let step_cnt = data.len() / 8;
+    for i in 0..step_cnt {
+        let x = vld1q_s16(data[i*8..i*8+8].as_ptr());
+        let low = vmulq_f32(vcvtq_f32_s32(vmovl_s16(vget_low_s16(x))), scale);
+        let high = vmulq_f32(vcvtq_f32_s32(vmovl_high_s16(x)), scale);
+        vst1q_f32(target[i*8..i*8+4].as_mut_ptr(), low);
+        vst1q_f32(target[i*8+4..i*8+8].as_mut_ptr(), high);
+    }
+    scalar_convert_pcm16_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn neon_convert_pcm32_to_f32(data: &[i32], target: &mut [f32]) {
+    let scale = vdupq_n_f32(1.0f32 / 2147483648.0);
+    let step_cnt = data.len() / 4;
+    for i in 0..step_cnt {
+        let x = vld1q_s32(data[i*4..i*4+4].as_ptr());
+        vst1q_f32(target[i*4..i*4+4].as_mut_ptr(), vmulq_f32(vcvtq_f32_s32(x), scale));
+    }
+    scalar_convert_pcm32_to_f32(&data[step_cnt*4..], &mut target[step_cnt*4..]);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn neon_convert_pcm8_to_f32(data: &[u8], target: &mut [f32]) {
+    let (offset, scale) = (vdupq_n_f32(128.0), vdupq_n_f32(1.0f32 / 128.0));
+    let step_cnt = data.len() / 8;
+    for i in 0..step_cnt {
+        // widened to u16, then each half to u32
+        let x = vmovl_u8(vld1_u8(data[i*8..i*8+8].as_ptr()));
+        let low = vcvtq_f32_u32(vmovl_u16(vget_low_u16(x)));
+        let high = vcvtq_f32_u32(vmovl_high_u16(x));
+        vst1q_f32(target[i*8..i*8+4].as_mut_ptr(), vmulq_f32(vsubq_f32(low, offset), scale));
+        vst1q_f32(target[i*8+4..i*8+8].as_mut_ptr(), vmulq_f32(vsubq_f32(high, offset), scale));
+    }
+    scalar_convert_pcm8_to_f32(&data[step_cnt*8..], &mut target[step_cnt*8..]);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn neon_convert_f64_to_f32(data: &[f64], target: &mut [f32]) {
+    let step_cnt = data.len() / 4;
+    for i in 0..step_cnt {
+        let low = vcvt_f32_f64(vld1q_f64(data[i*4..i*4+2].as_ptr()));
+        let w = vcvt_high_f32_f64(low, vld1q_f64(data[i*4+2..i*4+4].as_ptr()));
+        vst1q_f32(target[i*4..i*4+4].as_mut_ptr(), w);
+    }
+    scalar_convert_f64_to_f32(&data[step_cnt*4..], &mut target[step_cnt*4..]);
+}
+
+pub fn convert_pcm16_to_f32(data: &[i16], target: &mut [f32]) {
+    (kernels().pcm16)(data, target);
+}
+
+/// Triangular (TPDF) dither for the conversion to 16 bits: noise of up to one step
+/// added before rounding, so that quiet passages get a noise floor instead of the
+/// distortion plain rounding gives them.
+pub struct Dither {
+    // xorshift state, never 0
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    // uniform in [0, 1)
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// Noise in (-1, 1) steps, the difference of two uniform values.
+    fn next(&mut self) -> f32 {
+        self.uniform() - self.uniform()
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new(0x2545_f491)
+    }
+}
+
+/// Converts f32 samples in [-1.0, 1.0] to 16 bits, dithered if `dither` is given.
+/// Samples outside the range clip.
+pub fn convert_f32_to_pcm16(data: &[f32], target: &mut [i16], mut dither: Option<&mut Dither>) {
+    for i in 0..data.len() {
+        let noise = dither.as_deref_mut().map_or(0.0, Dither::next);
+        // the cast saturates
+        target[i] = (data[i] * 32768.0 + noise).round() as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    // odd lengths, so both the vector loop and the tail run
+    fn check<S: Sample>(data: &[S], scalar: impl Fn(S) -> f32) {
+        let mut target = vec![0.0f32; data.len()];
+        S::convert_to_f32(data, &mut target);
+        for (x, y) in data.iter().zip(&target) {
+            assert_eq!(*y, scalar(*x));
+        }
+    }
+
+    #[test]
+    fn integer_samples() {
+        check(&(-300..300).map(|i| i as i16 * 109).collect::<Vec<_>>(), |x| x as f32 / 32768.0);
+        check(&[i16::MIN, i16::MAX, 0, -1, 1, 7, 8, 9, 10], |x| x as f32 / 32768.0);
+        check(&(-300..301).map(|i| i * 7_000_001).collect::<Vec<i32>>(), |x| x as f32 / 2147483648.0);
+        check(&(0..=255).chain(0..20).collect::<Vec<u8>>(), |x| (x as f32 - 128.0) / 128.0);
+    }
+
+    #[test]
+    fn float_samples() {
+        check(&(0..101).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<_>>(), |x| x);
+        check(&(0..101).map(|i| (i as f64 * 0.37).sin()).collect::<Vec<_>>(), |x| x as f32);
+    }
+
+    // runs `kernel` of every supported set and compares it with the scalar one
+    fn same_as_scalar<S>(data: &[S], kernel: impl Fn(&Kernels) -> fn(&[S], &mut [f32])) -> Result<(), TestCaseError> {
+        let mut expected = vec![0.0f32; data.len()];
+        kernel(&SCALAR)(data, &mut expected);
+        for kernels in supported_kernels() {
+            let mut target = vec![0.0f32; data.len()];
+            kernel(kernels)(data, &mut target);
+            prop_assert_eq!(&target, &expected, "{} kernels", kernels.name);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn kernels_match_scalar(pcm16 in vec(any::<i16>(), 0..200), pcm32 in vec(any::<i32>(), 0..200),
+                                pcm8 in vec(any::<u8>(), 0..200), f64s in vec(-2.0f64..2.0, 0..200)) {
+            same_as_scalar(&pcm16, |k| k.pcm16)?;
+            same_as_scalar(&pcm32, |k| k.pcm32)?;
+            same_as_scalar(&pcm8, |k| k.pcm8)?;
+            same_as_scalar(&f64s, |k| k.f64)?;
+        }
+    }
+
+    #[test]
+    fn pcm16_round_trip() {
+        let data: Vec<i16> = (i16::MIN..=i16::MAX).step_by(7).collect();
+        let mut samples = vec![0.0f32; data.len()];
+        convert_pcm16_to_f32(&data, &mut samples);
+        let mut target = vec![0i16; data.len()];
+        convert_f32_to_pcm16(&samples, &mut target, None);
+        assert_eq!(target, data);
+        convert_f32_to_pcm16(&[1.5, -1.5], &mut target[..2], None);
+        assert_eq!(target[..2], [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn dither_stays_within_a_step() {
+        let mut dither = Dither::default();
+        let data: Vec<f32> = (0..10000).map(|i| (i as f32 * 0.01).sin() * 0.001).collect();
+        let (mut plain, mut dithered) = (vec![0i16; data.len()], vec![0i16; data.len()]);
+        convert_f32_to_pcm16(&data, &mut plain, None);
+        convert_f32_to_pcm16(&data, &mut dithered, Some(&mut dither));
+        assert!(plain.iter().zip(&dithered).all(|(x, y)| (x - y).abs() <= 1));
+        assert_ne!(plain, dithered);
+        // no bias
+        let error: f32 = data.iter().zip(&dithered).map(|(x, y)| *y as f32 - x * 32768.0).sum();
+        assert!((error / data.len() as f32).abs() < 0.05);
+    }
+}
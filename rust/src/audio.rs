@@ -1,102 +1,449 @@
 use ffmpeg_next as ffmpeg;
-use ffmpeg::{codec, format, frame, media};
+use ffmpeg::{codec, format, frame, media, Packet};
 use ffmpeg::software::resampler;
 use ffmpeg::ChannelLayout;
 use ffmpeg::format::Sample;
-use ffmpeg::ffi::AVSampleFormat;
-use pretty_hex::*;
 
 use anyhow::{Context, Result};
+use crate::avio::InputContext;
+use crate::channels::{ChannelSplitter, StreamOpener, TARGET_SAMPLE_RATE};
+use crate::clock::SampleClock;
+use crate::config::{LiveConfig, SessionConfig};
 use crate::errors::WhisperError;
-use log::{error, info, debug};
-use crate::rb::{Producer, RbProducer};
-use std::io::{Write};
+use crate::input::{AudioInput, StreamSelector};
+use crate::language::whisper_language;
+use crate::live::GapTracker;
+use log::{info, debug, warn};
+use crate::progress::{ProgressEvent, ProgressReporter, DECODE_PROGRESS_STEP};
+use crate::rb::OverflowPolicy;
+use crate::resample::ResamplerBackend;
+use std::time::{Duration, Instant};
 
-pub fn process_audio(audio_file: String, prod: Producer) -> Result<(), WhisperError> {
+fn ts_to_duration(ts: i64, time_base: ffmpeg::Rational) -> Option<Duration> {
+    if ts == ffmpeg::ffi::AV_NOPTS_VALUE || ts < 0 || time_base.denominator() == 0 {
+        return None;
+    }
+    let secs = ts as f64 * time_base.numerator() as f64 / time_base.denominator() as f64;
+    Some(Duration::from_secs_f64(secs))
+}
+
+fn select_stream<'a>(ictx: &'a format::context::Input, selector: &StreamSelector) -> Result<format::stream::Stream<'a>, WhisperError> {
+    let mut audio_streams = ictx.streams().filter(|s| s.parameters().medium() == media::Type::Audio);
+    let stream = match selector {
+        StreamSelector::Best => ictx.streams().best(media::Type::Audio),
+        StreamSelector::Index(index) => audio_streams.find(|s| s.index() == *index),
+        StreamSelector::Language(language) => {
+            // `eng`, `en` and `EN` all select an English stream
+            let wanted = whisper_language(language);
+            audio_streams.find(|s| s.metadata().get("language").is_some_and(|tag| {
+                tag.eq_ignore_ascii_case(language) || (wanted.is_some() && whisper_language(tag) == wanted)
+            }))
+        }
+        StreamSelector::Title(title) => {
+            let title = title.to_lowercase();
+            audio_streams.find(|s| s.metadata().get("title").is_some_and(|t| t.to_lowercase().contains(&title)))
+        }
+    };
+    stream.ok_or_else(|| WhisperError::StreamNotFound(format!("{:?}", selector)))
+}
+
+/// Keeps the converted samples between `start` and `end`, which count from the start of
+/// the stream like the segment times. Samples are counted at the converted rate from the
+/// first decoded frame, which is at or before `start` after a seek.
+struct Trim {
+    start: Option<Duration>,
+    end: Option<Duration>,
+    rate: u32,
+    // [first, last) in samples from the origin, known once the first frame is decoded
+    range: Option<(u64, u64)>,
+    pos: u64,
+}
+
+impl Trim {
+    fn new(start: Option<Duration>, end: Option<Duration>, rate: u32) -> Self {
+        Self { start, end, rate, range: None, pos: 0 }
+    }
 
-    let mut ictx = format::input(&audio_file)?;
-        //.context("failed to open input audio file")?;
+    /// `pts` is the timestamp of the first decoded frame, `stream_start` the one of the stream.
+    fn set_origin(&mut self, pts: Option<Duration>, stream_start: Duration) {
+        if self.range.is_some() {
+            return;
+        }
+        let origin = pts.unwrap_or(stream_start).saturating_sub(stream_start);
+        let to_samples = |time: Duration| (time.saturating_sub(origin).as_secs_f64() * self.rate as f64) as u64;
+        let first = self.start.map_or(0, to_samples);
+        let last = self.end.map_or(u64::MAX, to_samples);
+        self.range = Some((first, last));
+    }
 
-    let i_stream = ictx.streams().best(media::Type::Audio)
-        .context("failed to find audio stream")?;
+    /// Returns the part of `samples`, interleaved with `channels` channels, to keep,
+    /// and the number of frames cut before it.
+    fn keep<'a>(&mut self, samples: &'a [f32], channels: usize) -> (usize, &'a [f32]) {
+        let (first, last) = self.range.unwrap_or((0, u64::MAX));
+        let frames = (samples.len() / channels) as u64;
+        let from = first.clamp(self.pos, self.pos + frames) - self.pos;
+        let to = last.clamp(self.pos, self.pos + frames) - self.pos;
+        self.pos += frames;
+        (from as usize, &samples[from as usize * channels..to as usize * channels])
+    }
 
-    for (m_k, m_v) in i_stream.metadata().iter() {
-        info!("{}: {}", m_k, m_v);
+    fn done(&self) -> bool {
+        self.range.is_some_and(|(_, last)| self.pos >= last)
     }
+}
+
+/// The selected audio stream of an opened input, and its decoder.
+struct Source {
+    ictx: InputContext,
+    stream_idx: usize,
+    time_base: ffmpeg::Rational,
+    // first timestamp of the stream, transcript times count from it
+    start: Duration,
+    decoder: codec::decoder::Audio,
+    language: Option<String>,
+}
 
-    let audio_stream_idx = i_stream.index();
-    // create a decoder for the audio stream
-    let context_decoder = codec::context::Context::from_parameters(i_stream.parameters())
-        .context("failled to create decoder context")?;
+impl Source {
+    fn open(ictx: InputContext, config: &SessionConfig) -> Result<Self, WhisperError> {
+        for stream in ictx.streams().filter(|s| s.parameters().medium() == media::Type::Audio) {
+            for (m_k, m_v) in stream.metadata().iter() {
+                info!("stream {}: {}: {}", stream.index(), m_k, m_v);
+            }
+        }
+        let i_stream = select_stream(&ictx, &config.stream)?;
+        let language = i_stream.metadata().get("language").map(str::to_string);
 
-    let mut decoder = context_decoder.decoder().audio()
-        .context("audio decoder is required")?;
+        let stream_idx = i_stream.index();
+        let time_base = i_stream.time_base();
+        let start = ts_to_duration(i_stream.start_time(), time_base).unwrap_or_default();
+        // create a decoder for the audio stream
+        let context_decoder = codec::context::Context::from_parameters(i_stream.parameters())
+            .context("failled to create decoder context")?;
 
-    // logging info about the audio stream
-    info!("audio stream: index: {}, sample_fmt: {:?}, channel_layout: {:?}, rate: {}",
-          audio_stream_idx,
-          &decoder.format(),
-          &decoder.channel_layout(),
-          &decoder.rate());
+        let decoder = context_decoder.decoder().audio()
+            .context("audio decoder is required")?;
 
-    let sample_fmt = decoder.format();
+        // logging info about the audio stream
+        info!("audio stream: index: {}, sample_fmt: {:?}, channel_layout: {:?}, rate: {}",
+              stream_idx,
+              &decoder.format(),
+              &decoder.channel_layout(),
+              &decoder.rate());
 
-    let channel_layout = ChannelLayout::default(decoder.channels() as i32);
+        Ok(Source { ictx, stream_idx, time_base, start, decoder, language })
+    }
 
-    let target_channel_layout = ChannelLayout::default(1);
+    fn channel_layout(&self) -> ChannelLayout {
+        ChannelLayout::default(self.decoder.channels() as i32)
+    }
+}
+
+/// Converts decoded frames and writes them to the ring buffers. Outlives the
+/// sources of a live input, the output format is fixed by the first one.
+struct Output {
+    splitter: ChannelSplitter,
+    trim: Trim,
+    channel_layout: ChannelLayout,
+    channels: usize,
+    rate: u32,
+    // 16 kHz samples written to each stream
+    samples: usize,
+}
+
+impl Output {
+    fn open(source: &Source, mut opener: StreamOpener, config: &SessionConfig) -> Result<Self, WhisperError> {
+        // ffmpeg converts to packed f32, keeping the channels for the splitter;
+        // the rate is converted here too unless the sinc resampler does it
+        let rate = match config.resampler {
+            ResamplerBackend::Ffmpeg => TARGET_SAMPLE_RATE,
+            ResamplerBackend::Sinc(_) => source.decoder.rate(),
+        };
+        let channels = source.decoder.channels() as usize;
+        opener.set_language(source.language.clone());
+        let overflow = config.live.as_ref().map_or(OverflowPolicy::Block, |live| live.overflow);
+        let splitter = ChannelSplitter::open(config.channels, channels, rate, config.resampler.sinc_quality(),
+                                             &config.preprocess, overflow, opener)?;
+        Ok(Output {
+            splitter,
+            trim: Trim::new(config.start, config.end, rate),
+            channel_layout: source.channel_layout(),
+            channels,
+            rate,
+            samples: 0,
+        })
+    }
+
+    /// Writes a packed f32 frame to the splitter, `pts` is the time of its first sample.
+    fn write_frame(&mut self, frame: &frame::Audio, pts: Option<Duration>) -> Result<(), WhisperError> {
+        if frame.samples() == 0 {
+            return Ok(());
+        }
+        let data = frame.data(0);
+        let (cut, samples) = self.trim.keep(bytemuck::cast_slice(&data[..frame.samples() * self.channels * 4]),
+                                            self.channels);
+        let pts = pts.map(|pts| pts + Duration::from_secs_f64(cut as f64 / self.rate as f64));
+        self.samples += self.splitter.write_at(samples, pts)?;
+        Ok(())
+    }
+
+    fn write_silence(&mut self, len: Duration) -> Result<(), WhisperError> {
+        if !len.is_zero() {
+            warn!("filling a gap of {:?} with silence", len);
+            let samples = (len.as_secs_f64() * TARGET_SAMPLE_RATE as f64) as usize;
+            self.samples += self.splitter.write_silence(samples)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, progress: &ProgressReporter, duration: Option<Duration>) -> Result<(), WhisperError> {
+        self.samples += self.splitter.finish()?;
+        debug!("all samples cnt: {}", self.samples);
+        progress.report(ProgressEvent::Decode {
+            position: SampleClock::WHISPER.time(self.samples),
+            duration,
+        });
+        Ok(())
+    }
+}
+
+/// Decodes `source` into `output` until the input ends or `output` reached the end time.
+fn decode(source: &mut Source, output: &mut Output, mut gaps: Option<&mut GapTracker>,
+          progress: &ProgressReporter, duration: Option<Duration>) -> Result<(), WhisperError> {
+    let decoder = &mut source.decoder;
+    let channel_layout = ChannelLayout::default(decoder.channels() as i32);
+    let target_fmt = Sample::F32(ffmpeg::format::sample::Type::Packed);
     let mut resampler = resampler(
-        (sample_fmt, channel_layout, decoder.rate()),
-        (Sample::from(AVSampleFormat::AV_SAMPLE_FMT_S16), target_channel_layout, 16000)
-    ).unwrap();
-
-    let mut all_samples_cnt: usize = 0;
-
-    let audio_stream_time_base = i_stream.time_base();
-    // iterate packets of the audio stream
-    for (stream, mut packet) in ictx.packets() {
-        if stream.index() == audio_stream_idx {
-            // decode the packet
-            packet.rescale_ts(audio_stream_time_base, decoder.time_base());
-            decoder.send_packet(&packet).unwrap();
-            let mut decoded_frame = frame::Audio::empty();
-            while decoder.receive_frame(&mut decoded_frame).is_ok() {
-                let timestamp = decoded_frame.timestamp();
-                decoded_frame.set_pts(timestamp);
-                if decoder.rate() == 16000 {
-                    // if the audio stream is already at 16000 Hz, we don't need to resample it
-                    let data = decoded_frame.data(0);
-                    let fixed_data = bytemuck::cast_slice(&data[..decoded_frame.samples()*2]);
-                    prod.write_ext_blocking(fixed_data)?;
-                    continue;
-                }
-                // create a resampler to convert the audio to a different sample rate
-                let mut resampled_frame = frame::Audio::empty();
-                resampled_frame.set_format(Sample::from(AVSampleFormat::AV_SAMPLE_FMT_S16));
-                resampled_frame.set_channel_layout(channel_layout);
-                decoded_frame.set_format(decoder.format());
-                decoded_frame.set_channel_layout(channel_layout);
-                let mut delay_opt = resampler.run(&decoded_frame, &mut resampled_frame).unwrap();
-                // copy the resampled data to the decoded_data buffer
-                if resampled_frame.samples() > 0 {
-                    let data = resampled_frame.data(0);
-                    let fixed_data = bytemuck::cast_slice(&data[..resampled_frame.samples()*2]);
-                    prod.write_ext_blocking(fixed_data)?;
-                }
-                all_samples_cnt += resampled_frame.samples();
-                while let Some(delay) = delay_opt {
-                    delay_opt = resampler.flush(&mut resampled_frame).unwrap();
-                    let data = resampled_frame.data(0);
-                    let fixed_data = bytemuck::cast_slice(&data[..resampled_frame.samples()*2]);
-                    prod.write_ext_blocking(fixed_data)?;
-                    all_samples_cnt += resampled_frame.samples();
+        (decoder.format(), channel_layout, decoder.rate()),
+        (target_fmt, output.channel_layout, output.rate)
+    ).map_err(|e| WhisperError::Resample(e.to_string()))?;
+
+    let mut reported_position: Option<Duration> = None;
+    let mut packet = Packet::empty();
+    loop {
+        match packet.read(&mut source.ictx) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => break,
+            // a corrupt packet, the demuxer resyncs on the next one
+            Err(ffmpeg::Error::InvalidData) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        if packet.stream() != source.stream_idx {
+            continue;
+        }
+        if let Some(position) = packet.pts().and_then(|pts| ts_to_duration(pts, source.time_base)) {
+            if reported_position.is_none_or(|p| position >= p + DECODE_PROGRESS_STEP) {
+                progress.report(ProgressEvent::Decode { position, duration });
+                reported_position = Some(position);
+            }
+        }
+        // decode the packet
+        packet.rescale_ts(source.time_base, decoder.time_base());
+        decoder.send_packet(&packet)
+            .map_err(|e| WhisperError::Decode(e.to_string()))?;
+        let mut decoded_frame = frame::Audio::empty();
+        while decoder.receive_frame(&mut decoded_frame).is_ok() {
+            let timestamp = decoded_frame.timestamp();
+            decoded_frame.set_pts(timestamp);
+            let pts = timestamp.and_then(|ts| ts_to_duration(ts, decoder.time_base()));
+            output.trim.set_origin(pts, source.start);
+            // live inputs fill their gaps with silence, files keep the timestamps so that
+            // the segment times follow them across lost packets and jumps
+            let source_pts = match gaps.as_deref_mut() {
+                Some(gaps) => {
+                    let len = Duration::from_secs_f64(decoded_frame.samples() as f64 / decoder.rate() as f64);
+                    output.write_silence(gaps.frame(pts, len))?;
+                    None
                 }
+                None => pts.map(|pts| pts.saturating_sub(source.start)),
+            };
+            // convert to packed f32, at 16 kHz for the ffmpeg resampler backend
+            let mut resampled_frame = frame::Audio::empty();
+            resampled_frame.set_format(target_fmt);
+            resampled_frame.set_channel_layout(output.channel_layout);
+            decoded_frame.set_format(decoder.format());
+            decoded_frame.set_channel_layout(channel_layout);
+            let mut delay_opt = resampler.run(&decoded_frame, &mut resampled_frame)
+                .map_err(|e| WhisperError::Resample(e.to_string()))?;
+            output.write_frame(&resampled_frame, source_pts)?;
+            while delay_opt.is_some() {
+                delay_opt = resampler.flush(&mut resampled_frame)
+                    .map_err(|e| WhisperError::Resample(e.to_string()))?;
+                output.write_frame(&resampled_frame, None)?;
             }
+        }
+        if output.trim.done() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the selected audio stream of `input` from `config.start` to `config.end`
+/// into the ring buffers. With `config.live` set the input is reopened whenever it
+/// ends or fails, until `config.end` is reached or reconnecting fails.
+pub fn process_audio(input: AudioInput, opener: StreamOpener, config: &SessionConfig) -> Result<(), WhisperError> {
+    if let Some(live) = &config.live {
+        return match input {
+            AudioInput::Path(url) => process_live(&url, opener, config, live),
+            _ => Err(WhisperError::Decode("live mode needs a URL or path to reopen".to_string())),
+        };
+    }
+
+    let mut source = Source::open(InputContext::open(input)?, config)?;
 
+    if let Some(start) = config.start {
+        // lands on a seek point at or before `start`, the rest is trimmed after decoding
+        let ts = (source.start + start).as_micros() as i64;
+        if let Err(e) = source.ictx.seek(ts, ..ts) {
+            warn!("failed to seek to {:?}, decoding up to it instead: {}", start, e);
         }
     }
 
-    //println!("all samples : {:?}", decoded_data);
-    println!("all samples cnt: {}", all_samples_cnt);
-    prod.close();
-    Ok(())
+    let mut output = Output::open(&source, opener, config)?;
+    let duration = ts_to_duration(source.ictx.duration(), ffmpeg::rescale::TIME_BASE);
+    decode(&mut source, &mut output, None, &config.progress, duration)?;
+    output.finish(&config.progress, duration)
+}
+
+fn open_live(url: &str, config: &SessionConfig, live: &LiveConfig) -> Result<Source, WhisperError> {
+    let mut options = ffmpeg::Dictionary::new();
+    // makes a stalled connection fail instead of blocking forever
+    options.set("rw_timeout", &live.read_timeout.as_micros().to_string());
+    Source::open(InputContext::open_path_with_options(url, options)?, config)
+}
+
+/// Opens `url`, retrying as `live` allows.
+fn reconnect(url: &str, config: &SessionConfig, live: &LiveConfig) -> Result<Source, WhisperError> {
+    let mut attempts = 0;
+    loop {
+        match open_live(url, config, live) {
+            Ok(source) => return Ok(source),
+            Err(e) if live.max_reconnects.is_none_or(|max| attempts < max) => {
+                attempts += 1;
+                warn!("failed to open {}: {}, retrying in {:?}", url, e, live.reconnect_delay);
+                std::thread::sleep(live.reconnect_delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn process_live(url: &str, opener: StreamOpener, config: &SessionConfig, live: &LiveConfig) -> Result<(), WhisperError> {
+    let mut source = reconnect(url, config, live)?;
+    let mut output = Output::open(&source, opener, config)?;
+    let mut gaps = GapTracker::new(live.gap_threshold, live.max_gap);
+    loop {
+        let dropped = output.splitter.dropped();
+        let ret = decode(&mut source, &mut output, Some(&mut gaps), &config.progress, None);
+        if output.splitter.dropped() > dropped {
+            warn!("transcription can't keep up, dropped {} samples so far", output.splitter.dropped());
+        }
+        match ret {
+            Ok(()) if output.trim.done() => break,
+            Ok(()) => info!("{} ended, reconnecting", url),
+            // the inference side is gone, nothing to reconnect for
+            Err(e @ WhisperError::RbError(_)) => return Err(e),
+            Err(e) => warn!("{} failed: {}, reconnecting", url, e),
+        }
+        let down_since = Instant::now();
+        std::thread::sleep(live.reconnect_delay);
+        source = reconnect(url, config, live)?;
+        output.write_silence(gaps.reconnected(down_since.elapsed()))?;
+    }
+    output.finish(&config.progress, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::channels::OpenedStreams;
+    use crate::rb::{RbConsumer, RbError};
+
+    // a file stands in for the live server: every reconnect plays it again from the start
+    fn write_tone_wav(path: &std::path::Path, samples: usize) {
+        let data_size = samples as u32 * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&32000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_size.to_le_bytes());
+        for i in 0..samples {
+            let value = ((i as f32 * 440.0 / 16000.0 * std::f32::consts::TAU).sin() * 8000.0) as i16;
+            wav.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(path, wav).unwrap();
+    }
+
+    fn count_samples(opened: OpenedStreams) -> usize {
+        let mut cons = opened.consumers.into_iter().next().unwrap();
+        let mut pos = 0;
+        loop {
+            let view = match cons.peek_blocking(pos, 16000) {
+                Ok(view) => view,
+                Err(RbError::EOF) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let eof = view.is_eof();
+            pos = view.commit();
+            if eof {
+                break;
+            }
+        }
+        pos
+    }
+
+    #[test]
+    fn live_input_reconnects_until_end() {
+        let path = std::env::temp_dir().join(format!("whisper-live-{}.wav", std::process::id()));
+        write_tone_wav(&path, 16000);
+        let config = SessionConfig {
+            live: Some(LiveConfig { reconnect_delay: Duration::ZERO, max_reconnects: Some(1), ..Default::default() }),
+            end: Some(Duration::from_millis(2500)),
+            ..Default::default()
+        };
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let ret = process_audio(AudioInput::Path(path.to_string_lossy().into_owned()),
+                                StreamOpener::new(16000 * 10, opened_tx), &config);
+        std::fs::remove_file(&path).unwrap();
+        ret.unwrap();
+        // the 1 s file is played three times, with no gap between the connections
+        assert_eq!(count_samples(opened_rx.recv().unwrap()), 40000);
+    }
+
+    #[test]
+    fn trim_counts_from_the_stream_start() {
+        // an MPEG-TS stream starting at 10 s, seeked to a frame at 10.5 s for a 1 s to 2 s range
+        let mut trim = Trim::new(Some(Duration::from_secs(1)), Some(Duration::from_secs(2)), 16000);
+        trim.set_origin(Some(Duration::from_millis(10_500)), Duration::from_secs(10));
+        let samples = vec![0.0f32; 32000];
+        let (cut, kept) = trim.keep(&samples, 1);
+        assert_eq!((cut, kept.len()), (8000, 16000));
+        assert!(trim.done());
+        // the stream starts at 0, no seek
+        let mut trim = Trim::new(Some(Duration::from_secs(1)), None, 16000);
+        trim.set_origin(None, Duration::ZERO);
+        assert_eq!(trim.keep(&samples, 2).0, 16000);
+        assert!(!trim.done());
+    }
+
+    #[test]
+    fn live_input_gives_up_after_max_reconnects() {
+        let config = SessionConfig {
+            live: Some(LiveConfig { reconnect_delay: Duration::ZERO, max_reconnects: Some(2), ..Default::default() }),
+            ..Default::default()
+        };
+        let (opened_tx, _opened_rx) = mpsc::channel();
+        let ret = process_audio(AudioInput::Path("/nonexistent/stream.wav".to_string()),
+                                StreamOpener::new(16000, opened_tx), &config);
+        assert!(ret.is_err());
+    }
 }
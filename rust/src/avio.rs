@@ -0,0 +1,264 @@
+use ffmpeg_next as ffmpeg;
+use ffmpeg::ffi::*;
+use ffmpeg::format;
+use std::ffi::c_void;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::errors::WhisperError;
+use crate::input::{AudioInput, ReadSeek};
+
+const AVIO_BUFFER_SIZE: usize = 64 * 1024;
+
+enum IoSource {
+    Seekable(Box<dyn ReadSeek>),
+    Sequential(Box<dyn Read + Send>),
+}
+
+/// An opened ffmpeg input context. For in-memory and reader inputs the bytes are
+/// pulled through custom AVIO callbacks, nothing is written to disk.
+pub struct InputContext {
+    // dropped before the AVIO context it reads from
+    input: Option<format::context::Input>,
+    avio: *mut AVIOContext,
+    source: *mut IoSource,
+}
+
+unsafe impl Send for InputContext {}
+
+impl InputContext {
+    pub fn open(input: AudioInput) -> Result<Self, WhisperError> {
+        let source = match input {
+            AudioInput::Path(path) => {
+                return Ok(InputContext {
+                    input: Some(format::input(&path)?),
+                    avio: ptr::null_mut(),
+                    source: ptr::null_mut(),
+                });
+            }
+            AudioInput::Bytes(bytes) => IoSource::Seekable(Box::new(std::io::Cursor::new(bytes))),
+            AudioInput::Reader(reader) => IoSource::Seekable(reader),
+            AudioInput::Stream(stream) => IoSource::Sequential(stream),
+            AudioInput::Pcm(..) => {
+                return Err(WhisperError::Decode("raw pcm input doesn't go through ffmpeg".to_string()));
+            }
+            #[cfg(feature = "capture")]
+            AudioInput::Capture(_) => {
+                return Err(WhisperError::Decode("capture input doesn't go through ffmpeg".to_string()));
+            }
+        };
+        unsafe { Self::open_custom(source) }
+    }
+
+    /// Opens a path or URL with protocol and demuxer options.
+    pub fn open_path_with_options(path: &str, options: ffmpeg::Dictionary) -> Result<Self, WhisperError> {
+        Ok(InputContext {
+            input: Some(format::input_with_dictionary(&path, options)?),
+            avio: ptr::null_mut(),
+            source: ptr::null_mut(),
+        })
+    }
+
+    unsafe fn open_custom(source: IoSource) -> Result<Self, WhisperError> {
+        let seekable = matches!(source, IoSource::Seekable(_));
+        let buffer = av_malloc(AVIO_BUFFER_SIZE) as *mut u8;
+        if buffer.is_null() {
+            return Err(WhisperError::Decode("failed to allocate AVIO buffer".to_string()));
+        }
+        let opaque = Box::into_raw(Box::new(source));
+        let avio = avio_alloc_context(
+            buffer,
+            AVIO_BUFFER_SIZE as c_int,
+            0,
+            opaque as *mut c_void,
+            Some(read_packet),
+            None,
+            if seekable { Some(seek) } else { None },
+        );
+        // from here on `Drop` releases the buffer, the AVIO context and the source
+        let mut ctx = InputContext { input: None, avio, source: opaque };
+        if avio.is_null() {
+            av_free(buffer as *mut c_void);
+            return Err(WhisperError::Decode("failed to allocate AVIO context".to_string()));
+        }
+        if !seekable {
+            (*avio).seekable = 0;
+        }
+
+        let mut ps = avformat_alloc_context();
+        if ps.is_null() {
+            return Err(WhisperError::Decode("failed to allocate format context".to_string()));
+        }
+        (*ps).pb = avio;
+        (*ps).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+        // on failure avformat_open_input frees `ps`, but not our AVIO context
+        let ret = avformat_open_input(&mut ps, ptr::null(), ptr::null(), ptr::null_mut());
+        if ret < 0 {
+            return Err(ffmpeg::Error::from(ret).into());
+        }
+        let ret = avformat_find_stream_info(ps, ptr::null_mut());
+        if ret < 0 {
+            avformat_close_input(&mut ps);
+            return Err(ffmpeg::Error::from(ret).into());
+        }
+        ctx.input = Some(format::context::Input::wrap(ps));
+        Ok(ctx)
+    }
+}
+
+impl Deref for InputContext {
+    type Target = format::context::Input;
+
+    fn deref(&self) -> &Self::Target {
+        self.input.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for InputContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.input.as_mut().unwrap()
+    }
+}
+
+impl Drop for InputContext {
+    fn drop(&mut self) {
+        // closes the format context, custom IO is left to us
+        self.input.take();
+        unsafe {
+            if !self.avio.is_null() {
+                // ffmpeg may have replaced the buffer we allocated
+                av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+                avio_context_free(&mut self.avio);
+            }
+            if !self.source.is_null() {
+                drop(Box::from_raw(self.source));
+            }
+        }
+    }
+}
+
+// retries calls interrupted by a signal, like `Read::read_exact` does
+fn retry_interrupted<T>(mut f: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    loop {
+        match f() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            ret => return ret,
+        }
+    }
+}
+
+// user readers run inside the callbacks, their panics must not unwind into ffmpeg
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => Some(ret),
+        Err(_) => {
+            log::error!("audio input reader panicked");
+            None
+        }
+    }
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let source = &mut *(opaque as *mut IoSource);
+    let buf = std::slice::from_raw_parts_mut(buf, buf_size as usize);
+    let ret = catch_panic(|| retry_interrupted(|| match source {
+        IoSource::Seekable(reader) => reader.read(buf),
+        IoSource::Sequential(stream) => stream.read(buf),
+    }));
+    match ret {
+        Some(Ok(0)) => AVERROR_EOF,
+        Some(Ok(n)) => n as c_int,
+        Some(Err(e)) => {
+            log::error!("failed to read audio input: {}", e);
+            AVERROR_EXTERNAL
+        }
+        None => AVERROR_EXTERNAL,
+    }
+}
+
+unsafe extern "C" fn seek(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = match &mut *(opaque as *mut IoSource) {
+        IoSource::Seekable(reader) => reader,
+        IoSource::Sequential(_) => return -1,
+    };
+    if whence & AVSEEK_SIZE as c_int != 0 {
+        return catch_panic(|| stream_len(reader.as_mut()))
+            .and_then(|len| len.ok())
+            .map_or(-1, |len| len as i64);
+    }
+    let pos = match whence & !(AVSEEK_FORCE as c_int) {
+        0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    catch_panic(|| retry_interrupted(|| reader.seek(pos)))
+        .and_then(|pos| pos.ok())
+        .map_or(-1, |pos| pos as i64)
+}
+
+fn stream_len(reader: &mut dyn ReadSeek) -> std::io::Result<u64> {
+    let pos = retry_interrupted(|| reader.stream_position())?;
+    let len = retry_interrupted(|| reader.seek(SeekFrom::End(0)))?;
+    retry_interrupted(|| reader.seek(SeekFrom::Start(pos)))?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const JFK: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/samples/jfk.wav");
+
+    // a network stream: short reads, and a signal now and then
+    struct Flaky {
+        data: Cursor<Vec<u8>>,
+        calls: usize,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 3 == 0 {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(1000);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    struct Panicking;
+
+    impl Read for Panicking {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("reader bug")
+        }
+    }
+
+    // bytes of the packets of the audio stream
+    fn audio_bytes(input: AudioInput) -> usize {
+        let mut ctx = InputContext::open(input).unwrap();
+        let stream = ctx.streams().best(ffmpeg::media::Type::Audio).unwrap().index();
+        ctx.packets().filter(|(s, _)| s.index() == stream).map(|(_, packet)| packet.size()).sum()
+    }
+
+    #[test]
+    fn in_memory_inputs_match_the_file() {
+        let bytes = std::fs::read(JFK).unwrap();
+        let expected = audio_bytes(AudioInput::Path(JFK.to_string()));
+        // 11 s of 16 bit mono at 16 kHz
+        assert_eq!(expected, 352_000);
+        assert_eq!(audio_bytes(AudioInput::Bytes(bytes.clone())), expected);
+        assert_eq!(audio_bytes(AudioInput::reader(Cursor::new(bytes.clone()))), expected);
+        assert_eq!(audio_bytes(AudioInput::stream(Flaky { data: Cursor::new(bytes), calls: 0 })), expected);
+    }
+
+    #[test]
+    fn panicking_reader_fails_the_open() {
+        assert!(InputContext::open(AudioInput::stream(Panicking)).is_err());
+    }
+}
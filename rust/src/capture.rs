@@ -0,0 +1,277 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use log::{info, warn};
+
+use crate::channels::{ChannelSplitter, StreamOpener};
+use crate::config::{CancelToken, SessionConfig};
+use crate::errors::WhisperError;
+use crate::progress::ProgressEvent;
+use crate::rb::OverflowPolicy;
+
+// how often the capture loop checks `stop` while no audio arrives
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An audio input device, as listed by `capture_devices`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureDevice {
+    pub name: String,
+    pub is_default: bool,
+    /// Default capture format of the device.
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Captures from an input device until `stop` is triggered or `SessionConfig::end` is reached.
+#[derive(Debug, Clone)]
+pub struct CaptureSource {
+    /// Name of the device, or a part of it. The default input device if `None`.
+    pub device: Option<String>,
+    /// Audio buffered between the device and the transcription. Captured audio that
+    /// doesn't fit is dropped and reported as `ProgressEvent::Overrun`.
+    pub buffer: Duration,
+    /// Stops capturing. Unlike `SessionConfig::cancel`, what was captured is still transcribed.
+    pub stop: CancelToken,
+}
+
+impl Default for CaptureSource {
+    fn default() -> Self {
+        Self { device: None, buffer: Duration::from_secs(2), stop: CancelToken::new() }
+    }
+}
+
+fn capture_error<E: std::fmt::Display>(e: E) -> WhisperError {
+    WhisperError::Capture(e.to_string())
+}
+
+/// Lists the audio input devices of the default host.
+pub fn capture_devices() -> Result<Vec<CaptureDevice>, WhisperError> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().and_then(|device| device.name().ok());
+    let mut devices = Vec::new();
+    for device in host.input_devices().map_err(capture_error)? {
+        let Ok(name) = device.name() else { continue };
+        // devices that are listed but can't capture, e.g. busy or unplugged
+        let Ok(config) = device.default_input_config() else { continue };
+        devices.push(CaptureDevice {
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            name,
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+        });
+    }
+    Ok(devices)
+}
+
+fn find_device(name: Option<&str>) -> Result<cpal::Device, WhisperError> {
+    let host = cpal::default_host();
+    let Some(name) = name else {
+        return host.default_input_device().ok_or_else(|| capture_error("no default input device"));
+    };
+    let wanted = name.to_lowercase();
+    host.input_devices().map_err(capture_error)?
+        .find(|device| device.name().is_ok_and(|n| n.to_lowercase().contains(&wanted)))
+        .ok_or_else(|| capture_error(format!("no input device matches {:?}", name)))
+}
+
+/// The device callback side of the capture queue. Never blocks, blocks that would
+/// go over the capacity are dropped and counted.
+pub(crate) struct BlockSink {
+    blocks: mpsc::Sender<Vec<f32>>,
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+    overrun: Arc<AtomicU64>,
+}
+
+/// The transcription side of the capture queue.
+pub(crate) struct BlockSource {
+    blocks: mpsc::Receiver<Vec<f32>>,
+    queued: Arc<AtomicUsize>,
+    overrun: Arc<AtomicU64>,
+}
+
+/// Creates a queue holding up to `capacity` samples.
+pub(crate) fn block_queue(capacity: usize) -> (BlockSink, BlockSource) {
+    let (tx, rx) = mpsc::channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let overrun = Arc::new(AtomicU64::new(0));
+    let sink = BlockSink { blocks: tx, queued: queued.clone(), capacity, overrun: overrun.clone() };
+    (sink, BlockSource { blocks: rx, queued, overrun })
+}
+
+impl BlockSink {
+    pub(crate) fn push<T>(&self, samples: &[T]) where T: SizedSample, f32: FromSample<T> {
+        if self.queued.load(Ordering::Acquire) + samples.len() > self.capacity {
+            self.overrun.fetch_add(samples.len() as u64, Ordering::Relaxed);
+            return;
+        }
+        self.queued.fetch_add(samples.len(), Ordering::AcqRel);
+        let block = samples.iter().map(|sample| f32::from_sample_(*sample)).collect();
+        // the receiver is gone once capturing stopped, nothing to do about it here
+        let _ = self.blocks.send(block);
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, sink: BlockSink,
+                   error: Arc<Mutex<Option<String>>>) -> Result<cpal::Stream, WhisperError>
+    where T: SizedSample, f32: FromSample<T>
+{
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| sink.push(data),
+        move |e| {
+            warn!("capture stream error: {}", e);
+            *error.lock().unwrap() = Some(e.to_string());
+        },
+        None,
+    ).map_err(capture_error)
+}
+
+/// Captures from `source.device` into the ring buffers until stopped.
+pub fn process_capture(source: CaptureSource, opener: StreamOpener, config: &SessionConfig) -> Result<(), WhisperError> {
+    let device = find_device(source.device.as_deref())?;
+    let supported = device.default_input_config().map_err(capture_error)?;
+    let rate = supported.sample_rate().0;
+    let channels = supported.channels();
+    info!("capturing from {:?}: {:?}", device.name().unwrap_or_default(), supported);
+
+    let capacity = (source.buffer.as_secs_f64() * rate as f64) as usize * channels as usize;
+    let (sink, blocks) = block_queue(capacity);
+    let error = Arc::new(Mutex::new(None));
+    let stream_config = supported.config();
+    let stream = match supported.sample_format() {
+        SampleFormat::I8 => build_stream::<i8>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::I16 => build_stream::<i16>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::I32 => build_stream::<i32>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::U8 => build_stream::<u8>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::U16 => build_stream::<u16>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::F32 => build_stream::<f32>(&device, &stream_config, sink, error.clone()),
+        SampleFormat::F64 => build_stream::<f64>(&device, &stream_config, sink, error.clone()),
+        format => Err(capture_error(format!("unsupported sample format {:?}", format))),
+    }?;
+    stream.play().map_err(capture_error)?;
+
+    // the stream stops when dropped at the end of this function
+    run_capture(blocks, rate, channels, &source.stop, opener, config, || error.lock().unwrap().take())
+}
+
+/// Feeds captured blocks into the ring buffers in real time, independent of where they come from.
+/// `error` reports a failure of the device.
+pub(crate) fn run_capture<F>(blocks: BlockSource, rate: u32, channels: u16, stop: &CancelToken,
+                             opener: StreamOpener, config: &SessionConfig, error: F) -> Result<(), WhisperError>
+    where F: Fn() -> Option<String>
+{
+    // waits for inference like a file input does, the queue in front absorbs the wait
+    let mut splitter = ChannelSplitter::open(config.channels, channels as usize, rate,
+                                             config.resampler.sinc_quality(), &config.preprocess, OverflowPolicy::Block,
+                                             opener)?;
+    let end = config.end.map(|end| (end.as_secs_f64() * rate as f64) as u64);
+    let mut frames = 0u64;
+    let mut reported_overrun = 0u64;
+    while !stop.is_cancelled() && !config.cancel.is_cancelled() && end.is_none_or(|end| frames < end) {
+        if let Some(e) = error() {
+            return Err(WhisperError::Capture(e));
+        }
+        let block = match blocks.blocks.recv_timeout(POLL_INTERVAL) {
+            Ok(block) => block,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        blocks.queued.fetch_sub(block.len(), Ordering::AcqRel);
+        let block_frames = (block.len() / channels as usize) as u64;
+        let keep = end.map_or(block_frames, |end| block_frames.min(end - frames));
+        splitter.write(&block[..keep as usize * channels as usize])?;
+        frames += keep;
+
+        let overrun = blocks.overrun.load(Ordering::Relaxed);
+        if overrun > reported_overrun {
+            let to_duration = |samples: u64| Duration::from_secs_f64((samples / channels as u64) as f64 / rate as f64);
+            warn!("capture overrun, transcription is behind by more than the buffer, dropped {:?}",
+                  to_duration(overrun - reported_overrun));
+            config.progress.report(ProgressEvent::Overrun {
+                dropped: to_duration(overrun - reported_overrun),
+                total: to_duration(overrun),
+            });
+            reported_overrun = overrun;
+        }
+    }
+    splitter.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::OpenedStreams;
+    use crate::progress::ProgressReporter;
+    use crate::rb::{RbConsumer, RbError};
+
+    // drains the first stream, returns its length
+    fn drain(opened: OpenedStreams) -> usize {
+        let mut cons = opened.consumers.into_iter().next().unwrap();
+        let mut pos = 0;
+        loop {
+            let view = match cons.peek_blocking(pos, 16000) {
+                Ok(view) => view,
+                Err(RbError::EOF) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let eof = view.is_eof();
+            pos = view.commit();
+            if eof {
+                break;
+            }
+        }
+        pos
+    }
+
+    #[test]
+    fn null_device_is_captured_until_end() {
+        let (sink, source) = block_queue(48000 * 2);
+        // stands in for a 48 kHz stereo device delivering 10 ms callbacks of silence
+        let device = std::thread::spawn(move || {
+            for _ in 0..300 {
+                sink.push(&[0i16; 960]);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let config = SessionConfig { end: Some(Duration::from_secs(2)), ..Default::default() };
+        let (opened_tx, opened_rx) = mpsc::channel();
+        run_capture(source, 48000, 2, &CancelToken::new(), StreamOpener::new(16000 * 10, opened_tx), &config, || None)
+            .unwrap();
+        device.join().unwrap();
+        assert_eq!(drain(opened_rx.recv().unwrap()), 32000);
+    }
+
+    #[test]
+    fn full_queue_is_an_overrun() {
+        let (sink, source) = block_queue(1000);
+        for _ in 0..4 {
+            sink.push(&[0.0f32; 400]);
+        }
+        drop(sink);
+        let (events_tx, events_rx) = mpsc::channel();
+        let config = SessionConfig { progress: ProgressReporter::from_sender(events_tx), ..Default::default() };
+        let (opened_tx, opened_rx) = mpsc::channel();
+        run_capture(source, 16000, 1, &CancelToken::new(), StreamOpener::new(16000, opened_tx), &config, || None)
+            .unwrap();
+        assert_eq!(drain(opened_rx.recv().unwrap()), 800);
+        let overrun = events_rx.try_iter().find_map(|event| match event {
+            ProgressEvent::Overrun { total, .. } => Some(total),
+            _ => None,
+        });
+        assert_eq!(overrun, Some(Duration::from_secs_f64(800.0 / 16000.0)));
+    }
+
+    #[test]
+    fn device_error_stops_capture() {
+        let (_sink, source) = block_queue(1000);
+        let (opened_tx, _opened_rx) = mpsc::channel();
+        let ret = run_capture(source, 16000, 1, &CancelToken::new(), StreamOpener::new(16000, opened_tx),
+                              &SessionConfig::default(), || Some("device unplugged".to_string()));
+        assert!(matches!(ret, Err(WhisperError::Capture(_))));
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::clock::SampleClock;
+use crate::dsp::{AudioFilter, FilterChain, PreprocessConfig};
+use crate::errors::WhisperError;
+use crate::rb::{Consumer, OverflowPolicy, Producer, RbInspector, RbProducer, SpscRb, RB};
+use crate::resample::{ResampleQuality, SincResampler};
+
+pub(crate) const TARGET_SAMPLE_RATE: u32 = 16000;
+
+/// How the channels of the input are turned into the mono streams whisper transcribes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelPolicy {
+    /// Average all channels into one stream.
+    #[default]
+    Downmix,
+    /// Transcribe only the channel with this index, e.g. 0 for the left channel.
+    Select(usize),
+    /// Transcribe every channel on its own, e.g. stereo call recordings with
+    /// one speaker per channel.
+    Separate,
+}
+
+impl ChannelPolicy {
+    /// Number of streams to transcribe for an input with `channels` channels.
+    pub(crate) fn streams(&self, channels: usize) -> Result<usize, WhisperError> {
+        match self {
+            ChannelPolicy::Select(index) if *index >= channels => Err(WhisperError::Decode(format!(
+                "channel {} selected, the input has {} channels", index, channels))),
+            ChannelPolicy::Separate => Ok(channels),
+            _ => Ok(1),
+        }
+    }
+}
+
+/// What the decoder hands to the inference thread once it has opened the input.
+pub(crate) struct OpenedStreams {
+    pub consumers: Vec<Consumer>,
+    /// `language` tag of the selected audio stream.
+    pub language: Option<String>,
+}
+
+#[cfg(test)]
+impl OpenedStreams {
+    /// Reads every stream up to its end, once the producers are closed.
+    pub(crate) fn drain(self) -> Vec<Vec<f32>> {
+        use crate::rb::{RbConsumer, RbError};
+        self.consumers.into_iter().map(|mut cons| {
+            let mut samples = Vec::new();
+            loop {
+                let view = match cons.peek(samples.len(), 4096) {
+                    Ok(view) => view,
+                    Err(RbError::EOF) => break,
+                    Err(e) => panic!("{}", e),
+                };
+                let (first, second) = view.as_slices();
+                samples.extend_from_slice(first);
+                samples.extend_from_slice(second);
+                view.commit();
+            }
+            samples
+        }).collect()
+    }
+}
+
+/// Creates the ring buffers once the decoder knows how many streams it produces,
+/// and hands the consumers to the inference thread.
+pub(crate) struct StreamOpener {
+    capacity: usize,
+    opened: mpsc::Sender<OpenedStreams>,
+    language: Option<String>,
+}
+
+impl StreamOpener {
+    pub(crate) fn new(capacity: usize, opened: mpsc::Sender<OpenedStreams>) -> Self {
+        Self { capacity, opened, language: None }
+    }
+
+    #[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+    pub(crate) fn set_language(&mut self, language: Option<String>) {
+        self.language = language;
+    }
+
+    fn open(self, count: usize, overflow: OverflowPolicy) -> Result<Vec<Producer>, WhisperError> {
+        let (producers, consumers) = (0..count)
+            .map(|_| {
+                let rb = SpscRb::new(self.capacity);
+                (rb.producer().with_overflow(overflow), rb.consumer())
+            })
+            .unzip();
+        self.opened.send(OpenedStreams { consumers, language: self.language })
+            .map_err(|_| WhisperError::ChannelClosed)?;
+        Ok(producers)
+    }
+}
+
+struct Stream {
+    prod: Producer,
+    rate: u32,
+    resampler: Option<SincResampler>,
+    filters: FilterChain,
+    mono: Vec<f32>,
+    resampled: Vec<f32>,
+    // input frames and inserted 16 kHz silence so far, against the samples written,
+    // to tell how far the output lags the input
+    consumed: u64,
+    silence: u64,
+    written: u64,
+}
+
+impl Stream {
+    /// 16 kHz samples the resampler and the filters hold back.
+    fn lag(&self) -> u64 {
+        (self.consumed * TARGET_SAMPLE_RATE as u64 / self.rate as u64 + self.silence).saturating_sub(self.written)
+    }
+
+    fn write(&mut self, flush: bool, pts: Option<Duration>) -> Result<usize, WhisperError> {
+        // the first sample written now is from before the input, by the lag
+        let pts = pts.map(|pts| pts.saturating_sub(SampleClock::WHISPER.time(self.lag() as usize)));
+        if !flush {
+            self.consumed += self.mono.len() as u64;
+        }
+        let samples = match self.resampler.as_mut() {
+            Some(resampler) => {
+                self.resampled.clear();
+                if flush {
+                    resampler.flush(&mut self.resampled);
+                } else {
+                    resampler.process(&self.mono, &mut self.resampled);
+                }
+                &mut self.resampled
+            }
+            None if flush => {
+                self.resampled.clear();
+                &mut self.resampled
+            }
+            None => &mut self.mono,
+        };
+        self.filters.process(samples);
+        if flush {
+            self.filters.flush(samples);
+        }
+        // dropped samples count as written, for the position of the decoder
+        match pts {
+            Some(pts) => self.prod.write_at(samples.as_slice(), pts)?,
+            None => self.prod.write(samples.as_slice())?,
+        };
+        self.written += samples.len() as u64;
+        Ok(samples.len())
+    }
+
+    fn write_silence(&mut self, samples: usize) -> Result<usize, WhisperError> {
+        // through the filters, which may still hold back audio from before the silence
+        self.resampled.clear();
+        self.resampled.resize(samples, 0.0);
+        self.filters.process(&mut self.resampled);
+        self.prod.write(self.resampled.as_slice())?;
+        self.silence += samples as u64;
+        self.written += self.resampled.len() as u64;
+        Ok(self.resampled.len())
+    }
+}
+
+/// Splits interleaved f32 audio into the streams selected by a `ChannelPolicy`,
+/// resamples each to 16 kHz and writes it to its ring buffer.
+pub(crate) struct ChannelSplitter {
+    policy: ChannelPolicy,
+    channels: usize,
+    streams: Vec<Stream>,
+}
+
+impl ChannelSplitter {
+    /// `resampler` is the quality of the sinc resampler used when `rate` isn't 16 kHz,
+    /// `preprocess` the filters run on each stream after resampling. `overflow` decides what
+    /// happens to audio that doesn't fit in the ring buffers while inference catches up.
+    pub(crate) fn open(policy: ChannelPolicy, channels: usize, rate: u32, resampler: ResampleQuality,
+                       preprocess: &PreprocessConfig, overflow: OverflowPolicy, opener: StreamOpener) -> Result<Self, WhisperError> {
+        let count = policy.streams(channels)?;
+        let streams = opener.open(count, overflow)?
+            .into_iter()
+            .map(|prod| Stream {
+                prod,
+                rate,
+                resampler: (rate != TARGET_SAMPLE_RATE).then(|| SincResampler::new(rate, TARGET_SAMPLE_RATE, resampler)),
+                filters: preprocess.chain(TARGET_SAMPLE_RATE),
+                mono: Vec::new(),
+                resampled: Vec::new(),
+                consumed: 0,
+                silence: 0,
+                written: 0,
+            })
+            .collect();
+        Ok(Self { policy, channels, streams })
+    }
+
+    /// Writes whole frames of interleaved samples.
+    /// Returns the number of 16 kHz samples written to each stream.
+    pub(crate) fn write(&mut self, samples: &[f32]) -> Result<usize, WhisperError> {
+        self.write_at(samples, None)
+    }
+
+    /// Works like `write`, `pts` is the time of the first frame in the source media.
+    /// The ring buffers record where it doesn't continue the frames before.
+    pub(crate) fn write_at(&mut self, samples: &[f32], pts: Option<Duration>) -> Result<usize, WhisperError> {
+        let frames = samples.chunks_exact(self.channels);
+        match self.policy {
+            ChannelPolicy::Downmix => {
+                let scale = 1.0 / self.channels as f32;
+                self.streams[0].mono.clear();
+                self.streams[0].mono.extend(frames.map(|frame| frame.iter().sum::<f32>() * scale));
+            }
+            ChannelPolicy::Select(index) => {
+                self.streams[0].mono.clear();
+                self.streams[0].mono.extend(frames.map(|frame| frame[index]));
+            }
+            ChannelPolicy::Separate => {
+                for (channel, stream) in self.streams.iter_mut().enumerate() {
+                    stream.mono.clear();
+                    stream.mono.extend(frames.clone().map(|frame| frame[channel]));
+                }
+            }
+        }
+        self.write_streams(false, pts)
+    }
+
+    fn write_streams(&mut self, flush: bool, pts: Option<Duration>) -> Result<usize, WhisperError> {
+        let mut written = 0;
+        for stream in self.streams.iter_mut() {
+            written = stream.write(flush, pts)?;
+        }
+        Ok(written)
+    }
+
+    /// Writes `samples` 16 kHz samples of silence to every stream.
+    #[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+    pub(crate) fn write_silence(&mut self, samples: usize) -> Result<usize, WhisperError> {
+        for stream in self.streams.iter_mut() {
+            stream.write_silence(samples)?;
+        }
+        Ok(samples)
+    }
+
+    /// Samples dropped so far by the first stream, see `overflow`.
+    #[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+    pub(crate) fn dropped(&self) -> u64 {
+        let prod = &self.streams[0].prod;
+        prod.dropped_oldest() + prod.dropped_newest()
+    }
+
+    /// Flushes the resamplers and closes the ring buffers.
+    /// Returns the number of samples written to each stream by the flush.
+    pub(crate) fn finish(mut self) -> Result<usize, WhisperError> {
+        let written = self.write_streams(true, None)?;
+        for stream in &self.streams {
+            RbProducer::close(&stream.prod);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(policy: ChannelPolicy, channels: usize, samples: &[f32]) -> Result<Vec<Vec<f32>>, WhisperError> {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let mut splitter = ChannelSplitter::open(policy, channels, TARGET_SAMPLE_RATE, ResampleQuality::default(),
+                                                 &PreprocessConfig::default(), OverflowPolicy::Block,
+                                                 StreamOpener::new(1024, opened_tx))?;
+        splitter.write(samples)?;
+        splitter.finish()?;
+        Ok(opened_rx.recv().unwrap().drain())
+    }
+
+    // left 0.5, 0.25, 0.0; right -0.25, 0.75, 1.0
+    const STEREO: [f32; 6] = [0.5, -0.25, 0.25, 0.75, 0.0, 1.0];
+
+    #[test]
+    fn downmix_averages() {
+        assert_eq!(split(ChannelPolicy::Downmix, 2, &STEREO).unwrap(), vec![vec![0.125, 0.5, 0.5]]);
+    }
+
+    #[test]
+    fn select_takes_one_channel() {
+        assert_eq!(split(ChannelPolicy::Select(0), 2, &STEREO).unwrap(), vec![vec![0.5, 0.25, 0.0]]);
+        assert_eq!(split(ChannelPolicy::Select(1), 2, &STEREO).unwrap(), vec![vec![-0.25, 0.75, 1.0]]);
+        assert!(matches!(split(ChannelPolicy::Select(2), 2, &STEREO), Err(WhisperError::Decode(_))));
+    }
+
+    #[test]
+    fn separate_streams_per_channel() {
+        assert_eq!(split(ChannelPolicy::Separate, 2, &STEREO).unwrap(),
+                   vec![vec![0.5, 0.25, 0.0], vec![-0.25, 0.75, 1.0]]);
+        // an incomplete trailing frame is left out
+        assert_eq!(split(ChannelPolicy::Separate, 3, &STEREO[..5]).unwrap(),
+                   vec![vec![0.5], vec![-0.25], vec![0.25]]);
+    }
+}
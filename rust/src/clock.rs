@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Converts between sample positions, `Duration`s and the 10 ms units of whisper's
+/// segment timestamps (`t0`, `t1`) at a sample rate.
+///
+/// Positions count the samples since the start of the stream, a position's time is
+/// when that sample starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleClock {
+    rate: u32,
+}
+
+impl SampleClock {
+    /// The 16 kHz whisper transcribes at, the rate of the ring buffers.
+    pub const WHISPER: SampleClock = SampleClock { rate: 16000 };
+
+    pub const fn new(rate: u32) -> Self {
+        Self { rate }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Position of the sample playing at `time`.
+    pub fn position(&self, time: Duration) -> usize {
+        (time.as_nanos() * self.rate as u128 / NANOS_PER_SEC) as usize
+    }
+
+    /// Time at which the sample at `pos` starts.
+    pub fn time(&self, pos: usize) -> Duration {
+        let nanos = pos as u128 * NANOS_PER_SEC / self.rate as u128;
+        Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    /// Position of the sample at a whisper timestamp.
+    pub fn position_of_centis(&self, centis: i64) -> usize {
+        self.position(Self::centis_to_duration(centis))
+    }
+
+    /// Whisper timestamp of the sample at `pos`, rounded down.
+    pub fn centis(&self, pos: usize) -> i64 {
+        (pos as u128 * 100 / self.rate as u128) as i64
+    }
+
+    /// Converts a whisper timestamp. Whisper reports -1 for unknown times, negative
+    /// timestamps are taken as 0.
+    pub fn centis_to_duration(centis: i64) -> Duration {
+        Duration::from_millis(centis.max(0) as u64 * 10)
+    }
+}
+
+impl Default for SampleClock {
+    fn default() -> Self {
+        Self::WHISPER
+    }
+}
+
+/// The samples from `pos` on start at `pts` in the source media.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelineMark {
+    pub pos: usize,
+    pub pts: Duration,
+}
+
+/// Maps sample positions to times in the source media, across the packet losses and
+/// timestamp jumps the decoded samples don't show.
+///
+/// A mark is where the timestamps don't continue the ones before, the samples after
+/// it follow at the rate of the clock.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timeline {
+    clock: SampleClock,
+    marks: Vec<TimelineMark>,
+}
+
+impl Timeline {
+    /// `marks` ordered by position.
+    pub fn new(clock: SampleClock, marks: Vec<TimelineMark>) -> Self {
+        Self { clock, marks }
+    }
+
+    pub fn marks(&self) -> &[TimelineMark] {
+        &self.marks
+    }
+
+    /// Time of the sample at `pos` in the source, `None` before the first mark.
+    pub fn source_time(&self, pos: usize) -> Option<Duration> {
+        let mark = self.marks.iter().rev().find(|mark| mark.pos <= pos)?;
+        Some(mark.pts + self.clock.time(pos - mark.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_and_times() {
+        let clock = SampleClock::WHISPER;
+        assert_eq!(clock.position(Duration::from_millis(1500)), 24000);
+        assert_eq!(clock.time(24000), Duration::from_millis(1500));
+        // a time between two samples belongs to the earlier one
+        assert_eq!(clock.position(Duration::from_micros(62)), 0);
+        assert_eq!(clock.position(Duration::from_micros(63)), 1);
+        // ten hours don't overflow
+        assert_eq!(clock.time(clock.position(Duration::from_secs(36000))), Duration::from_secs(36000));
+    }
+
+    #[test]
+    fn other_rates() {
+        let clock = SampleClock::new(44100);
+        assert_eq!(clock.position(Duration::from_secs(2)), 88200);
+        assert_eq!(clock.time(1), Duration::from_nanos(22675));
+    }
+
+    #[test]
+    fn timeline_follows_the_marks() {
+        let timeline = Timeline::new(SampleClock::WHISPER, vec![
+            TimelineMark { pos: 1600, pts: Duration::from_secs(10) },
+            // 2 s lost after 1 s
+            TimelineMark { pos: 17600, pts: Duration::from_secs(13) },
+        ]);
+        assert_eq!(timeline.source_time(0), None);
+        assert_eq!(timeline.source_time(9600), Some(Duration::from_millis(10500)));
+        assert_eq!(timeline.source_time(17600), Some(Duration::from_secs(13)));
+        assert_eq!(timeline.source_time(25600), Some(Duration::from_millis(13500)));
+    }
+
+    #[test]
+    fn whisper_timestamps() {
+        let clock = SampleClock::WHISPER;
+        assert_eq!(clock.position_of_centis(150), 24000);
+        assert_eq!(clock.centis(24159), 150);
+        assert_eq!(clock.position_of_centis(-1), 0);
+        assert_eq!(SampleClock::centis_to_duration(42), Duration::from_millis(420));
+    }
+}
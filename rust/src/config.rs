@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::channels::ChannelPolicy;
+use crate::dsp::PreprocessConfig;
+use crate::input::StreamSelector;
+use crate::language::LanguageHint;
+use crate::progress::ProgressReporter;
+use crate::rb::OverflowPolicy;
+use crate::resample::ResamplerBackend;
+use crate::transcript::FailurePolicy;
+
+pub const DEFAULT_MODEL_PATH: &str = "/media/msd/models/ggml-large-v3-q5_0.bin";
+
+/// Settings for one transcription session.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Path of the ggml whisper model.
+    pub model_path: String,
+    /// Receives decode and inference progress.
+    pub progress: ProgressReporter,
+    /// Cancels the session from another thread.
+    pub cancel: CancelToken,
+    /// What to do when whisper fails on a chunk.
+    pub failure_policy: FailurePolicy,
+    /// Resampler used when the input isn't 16 kHz.
+    pub resampler: ResamplerBackend,
+    /// Which channels are transcribed, and whether separately.
+    pub channels: ChannelPolicy,
+    /// Filters run on each 16 kHz stream before it's transcribed.
+    pub preprocess: PreprocessConfig,
+    /// Speaker names by channel index, used to label the segments,
+    /// e.g. `["agent", "customer"]` with `ChannelPolicy::Separate`.
+    pub speakers: Vec<String>,
+    /// Audio stream to transcribe from multi-stream containers.
+    pub stream: StreamSelector,
+    /// Transcribe from this time on. Containers are seeked, not decoded up to it.
+    pub start: Option<Duration>,
+    /// Stop transcribing at this time.
+    pub end: Option<Duration>,
+    /// Language passed to whisper.
+    pub language: LanguageHint,
+    /// Treat `AudioInput::Path` as an unbounded stream, e.g. an RTSP, RTMP, HLS or Icecast URL.
+    pub live: Option<LiveConfig>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            model_path: DEFAULT_MODEL_PATH.to_string(),
+            progress: ProgressReporter::disabled(),
+            cancel: CancelToken::new(),
+            failure_policy: FailurePolicy::default(),
+            resampler: ResamplerBackend::default(),
+            channels: ChannelPolicy::default(),
+            preprocess: PreprocessConfig::default(),
+            speakers: Vec::new(),
+            stream: StreamSelector::default(),
+            start: None,
+            end: None,
+            language: LanguageHint::default(),
+            live: None,
+        }
+    }
+}
+
+/// Settings for live inputs. They are reopened when they fail or end, and audio
+/// the transcription can't keep up with is dropped instead of buffered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveConfig {
+    /// Wait before reopening the input.
+    pub reconnect_delay: Duration,
+    /// Give up after this many failed attempts in a row, `None` to retry forever.
+    pub max_reconnects: Option<u32>,
+    /// A read stalled for longer than this fails and triggers a reconnect.
+    pub read_timeout: Duration,
+    /// Timestamp jumps and outages longer than this are filled with silence.
+    pub gap_threshold: Duration,
+    /// Longest silence inserted for a single gap.
+    pub max_gap: Duration,
+    /// What happens to audio the ring buffer has no room for. Dropping the oldest keeps
+    /// the transcription close to real time, `OverflowPolicy::Block` stalls the input.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_delay: Duration::from_secs(2),
+            max_reconnects: None,
+            read_timeout: Duration::from_secs(10),
+            gap_threshold: Duration::from_millis(500),
+            max_gap: Duration::from_secs(30),
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// Shared flag to stop a running session. The session returns `WhisperError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
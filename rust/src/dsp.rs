@@ -0,0 +1,692 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+/// A stage of the preprocessing chain, run on each mono 16 kHz stream before it's transcribed.
+pub trait AudioFilter: Send {
+    /// Filters a block of samples in place. A filter may hold samples back and
+    /// return them with a later block, so the block can change length.
+    fn process(&mut self, samples: &mut Vec<f32>);
+
+    /// Appends the samples still held back at the end of the stream.
+    fn flush(&mut self, _samples: &mut Vec<f32>) {}
+}
+
+/// Filters run one after the other.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn AudioFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a filter to the end of the chain.
+    pub fn push<F: AudioFilter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl AudioFilter for FilterChain {
+    fn process(&mut self, samples: &mut Vec<f32>) {
+        for filter in self.filters.iter_mut() {
+            filter.process(samples);
+        }
+    }
+
+    fn flush(&mut self, samples: &mut Vec<f32>) {
+        // what a filter flushes still goes through the filters after it
+        let mut tail = Vec::new();
+        for filter in self.filters.iter_mut() {
+            filter.process(&mut tail);
+            filter.flush(&mut tail);
+        }
+        samples.extend(tail);
+    }
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+// one-pole smoothing coefficient reaching ~63% of a step after `seconds`
+fn smoothing(seconds: f64, rate: u32) -> f32 {
+    (1.0 - (-1.0 / (seconds * rate as f64)).exp()) as f32
+}
+
+/// Transposed direct form II biquad.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self { b: b.map(|b| b / a[0]), a: [a[1] / a[0], a[2] / a[0]], z: [0.0; 2] }
+    }
+
+    fn high_pass(cutoff: f64, q: f64, rate: u32) -> Self {
+        let w0 = 2.0 * PI * cutoff / rate as f64;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2.0 * q));
+        Self::new([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn high_shelf(freq: f64, gain_db: f64, q: f64, rate: u32) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / rate as f64;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2.0 * q));
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ],
+        )
+    }
+
+    fn run(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// Second order Butterworth high-pass, removes DC offset and low frequency rumble.
+pub struct HighPass {
+    biquad: Biquad,
+}
+
+impl HighPass {
+    pub fn new(cutoff_hz: f32, rate: u32) -> Self {
+        Self { biquad: Biquad::high_pass(cutoff_hz as f64, std::f64::consts::FRAC_1_SQRT_2, rate) }
+    }
+}
+
+impl AudioFilter for HighPass {
+    fn process(&mut self, samples: &mut Vec<f32>) {
+        for sample in samples.iter_mut() {
+            *sample = self.biquad.run(*sample as f64) as f32;
+        }
+    }
+}
+
+// loudness below this is silence, the absolute gate of EBU R128
+const SILENCE_LUFS: f64 = -70.0;
+
+/// Integrated loudness as specified by ITU-R BS.1770 and EBU R128, for a mono signal.
+struct LoudnessMeter {
+    k_weighting: [Biquad; 2],
+    // 100 ms steps, gating blocks are 400 ms with 75% overlap
+    step_len: usize,
+    step_pos: usize,
+    step_energy: f64,
+    steps: VecDeque<f64>,
+    // mean square of the blocks above the absolute gate
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    fn new(rate: u32) -> Self {
+        Self {
+            k_weighting: [
+                Biquad::high_shelf(1681.974450955533, 3.999843853973347, 0.7071752369554196, rate),
+                Biquad::high_pass(38.13547087602444, 0.5003270373238773, rate),
+            ],
+            step_len: rate as usize / 10,
+            step_pos: 0,
+            step_energy: 0.0,
+            steps: VecDeque::with_capacity(4),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        let weighted = self.k_weighting.iter_mut().fold(sample as f64, |x, biquad| biquad.run(x));
+        self.step_energy += weighted * weighted;
+        self.step_pos += 1;
+        if self.step_pos < self.step_len {
+            return;
+        }
+        if self.steps.len() == 4 {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy / self.step_len as f64);
+        self.step_pos = 0;
+        self.step_energy = 0.0;
+        if self.steps.len() < 4 {
+            return;
+        }
+        let block = self.steps.iter().sum::<f64>() / 4.0;
+        if loudness(block) > SILENCE_LUFS {
+            self.blocks.push(block);
+        }
+    }
+
+    /// True right after a sample completed a 100 ms step.
+    fn step_done(&self) -> bool {
+        self.step_pos == 0
+    }
+
+    /// Loudness in LUFS of what was measured so far, `None` while it's all silence.
+    fn integrated(&self) -> Option<f64> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let mean = self.blocks.iter().sum::<f64>() / self.blocks.len() as f64;
+        let relative_gate = loudness(mean) - 10.0;
+        let (sum, count) = self.blocks.iter()
+            .filter(|block| loudness(**block) > relative_gate)
+            .fold((0.0, 0), |(sum, count), block| (sum + block, count + 1));
+        Some(loudness(sum / count as f64))
+    }
+}
+
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Level the normalizer scales to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Peak level in dBFS.
+    Peak(f32),
+    /// RMS level over the last 3 seconds of non-silent audio, in dBFS.
+    Rms(f32),
+    /// EBU R128 integrated loudness in LUFS, e.g. -23.
+    Loudness(f32),
+}
+
+/// Settings of the `Normalizer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizeConfig {
+    pub target: Normalization,
+    /// Largest gain applied, so silence and noise aren't boosted without limit.
+    pub max_gain_db: f32,
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        Self { target: Normalization::Loudness(-23.0), max_gain_db: 30.0 }
+    }
+}
+
+/// Scales the audio to a target level. The input is a stream, so the level is
+/// measured on what was heard so far and the gain follows it smoothly.
+pub struct Normalizer {
+    config: NormalizeConfig,
+    loudness: LoudnessMeter,
+    // mean square of the last 3 s of non-silent 100 ms steps, for `Normalization::Rms`
+    rms_steps: VecDeque<f64>,
+    rms_energy: f64,
+    peak: f32,
+    gain: f32,
+    target_gain: Option<f32>,
+    smoothing: f32,
+}
+
+impl Normalizer {
+    pub fn new(config: NormalizeConfig, rate: u32) -> Self {
+        Self {
+            config,
+            loudness: LoudnessMeter::new(rate),
+            rms_steps: VecDeque::new(),
+            rms_energy: 0.0,
+            peak: 0.0,
+            gain: 1.0,
+            target_gain: None,
+            smoothing: smoothing(0.5, rate),
+        }
+    }
+
+    fn measure(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.rms_energy += (sample * sample) as f64;
+        self.loudness.push(sample);
+        // the gain is updated every 100 ms
+        if !self.loudness.step_done() {
+            return;
+        }
+        let mean_square = self.rms_energy / self.loudness.step_len as f64;
+        self.rms_energy = 0.0;
+        if 10.0 * mean_square.log10() > SILENCE_LUFS {
+            if self.rms_steps.len() == 30 {
+                self.rms_steps.pop_front();
+            }
+            self.rms_steps.push_back(mean_square);
+        }
+        let gain_db = match self.config.target {
+            Normalization::Peak(target) if self.peak > 0.0 => target as f64 - 20.0 * (self.peak as f64).log10(),
+            Normalization::Rms(target) if !self.rms_steps.is_empty() => {
+                let mean_square = self.rms_steps.iter().sum::<f64>() / self.rms_steps.len() as f64;
+                target as f64 - 10.0 * mean_square.log10()
+            }
+            Normalization::Loudness(target) => match self.loudness.integrated() {
+                Some(loudness) => target as f64 - loudness,
+                None => return,
+            },
+            _ => return,
+        };
+        let gain = db_to_gain(gain_db.min(self.config.max_gain_db as f64)) as f32;
+        if self.target_gain.is_none() {
+            // start at the first measured level instead of fading in
+            self.gain = gain;
+        }
+        self.target_gain = Some(gain);
+    }
+}
+
+impl AudioFilter for Normalizer {
+    fn process(&mut self, samples: &mut Vec<f32>) {
+        for sample in samples.iter_mut() {
+            self.measure(*sample);
+            if let Some(target) = self.target_gain {
+                self.gain += (target - self.gain) * self.smoothing;
+            }
+            *sample = (*sample * self.gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Settings of the `NoiseGate`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseGateConfig {
+    /// The gate opens when the level goes above this, in dBFS.
+    pub threshold_db: f32,
+    /// Attenuation while closed, in dB. Closing fully makes whisper more likely to hallucinate.
+    pub floor_db: f32,
+    pub attack_ms: f32,
+    /// How long the gate stays open after the level dropped below the threshold.
+    pub hold_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for NoiseGateConfig {
+    fn default() -> Self {
+        Self { threshold_db: -45.0, floor_db: -30.0, attack_ms: 1.0, hold_ms: 200.0, release_ms: 150.0 }
+    }
+}
+
+/// Attenuates the audio while its level stays below a threshold.
+pub struct NoiseGate {
+    threshold: f32,
+    floor: f32,
+    hold: usize,
+    attack: f32,
+    release: f32,
+    // peak envelope decay per sample
+    decay: f32,
+    envelope: f32,
+    held: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(config: NoiseGateConfig, rate: u32) -> Self {
+        let seconds = |ms: f32| (ms as f64 / 1000.0).max(1.0 / rate as f64);
+        Self {
+            threshold: db_to_gain(config.threshold_db as f64) as f32,
+            floor: db_to_gain(config.floor_db as f64) as f32,
+            hold: (seconds(config.hold_ms) * rate as f64) as usize,
+            attack: smoothing(seconds(config.attack_ms), rate),
+            release: smoothing(seconds(config.release_ms), rate),
+            decay: 1.0 - smoothing(0.01, rate),
+            envelope: 0.0,
+            held: 0,
+            gain: db_to_gain(config.floor_db as f64) as f32,
+        }
+    }
+}
+
+impl AudioFilter for NoiseGate {
+    fn process(&mut self, samples: &mut Vec<f32>) {
+        for sample in samples.iter_mut() {
+            self.envelope = sample.abs().max(self.envelope * self.decay);
+            let target = if self.envelope > self.threshold {
+                self.held = self.hold;
+                1.0
+            } else if self.held > 0 {
+                self.held -= 1;
+                1.0
+            } else {
+                self.floor
+            };
+            let speed = if target > self.gain { self.attack } else { self.release };
+            self.gain += (target - self.gain) * speed;
+            *sample *= self.gain;
+        }
+    }
+}
+
+/// Settings of the `SpectralDenoiser`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DenoiseConfig {
+    /// How much of the estimated noise is subtracted, above 1 to suppress more.
+    pub strength: f32,
+    /// Lowest gain of a frequency bin in dB, limits the "musical noise" of spectral subtraction.
+    pub floor_db: f32,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self { strength: 1.5, floor_db: -20.0 }
+    }
+}
+
+const DENOISE_FRAME: usize = 512;
+const DENOISE_HOP: usize = DENOISE_FRAME / 2;
+const NOISE_BIAS: f32 = 2.0;
+
+/// Spectral subtraction of stationary noise, e.g. hum, hiss and fans.
+///
+/// The noise spectrum follows the minimum of the smoothed power of each bin, it falls
+/// right away and rises by at most 3 dB a second, so speech isn't mistaken for noise.
+pub struct SpectralDenoiser {
+    strength: f32,
+    floor: f32,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    // square root of a periodic Hann window, applied before and after the FFT
+    window: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    smoothed: Vec<f32>,
+    noise: Vec<f32>,
+    noise_rise: f32,
+    frames: usize,
+    // input not yet part of a full frame, starts with a hop of zeros
+    pending: Vec<f32>,
+    overlap: Vec<f32>,
+    // output of the zero padding still to drop
+    skip: usize,
+    received: u64,
+    emitted: u64,
+}
+
+impl SpectralDenoiser {
+    pub fn new(config: DenoiseConfig, rate: u32) -> Self {
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(DENOISE_FRAME);
+        let inverse = planner.plan_fft_inverse(DENOISE_FRAME);
+        let scratch_len = forward.get_inplace_scratch_len().max(inverse.get_inplace_scratch_len());
+        let window = (0..DENOISE_FRAME)
+            .map(|n| (0.5 - 0.5 * (2.0 * PI * n as f64 / DENOISE_FRAME as f64).cos()).sqrt() as f32)
+            .collect();
+        Self {
+            strength: config.strength,
+            floor: db_to_gain(config.floor_db as f64) as f32,
+            forward,
+            inverse,
+            window,
+            spectrum: vec![Complex::default(); DENOISE_FRAME],
+            scratch: vec![Complex::default(); scratch_len],
+            smoothed: vec![0.0; DENOISE_FRAME / 2 + 1],
+            noise: vec![0.0; DENOISE_FRAME / 2 + 1],
+            noise_rise: 2f32.powf(0.5 * DENOISE_HOP as f32 / rate as f32),
+            frames: 0,
+            pending: vec![0.0; DENOISE_FRAME - DENOISE_HOP],
+            overlap: vec![0.0; DENOISE_FRAME],
+            skip: DENOISE_FRAME - DENOISE_HOP,
+            received: 0,
+            emitted: 0,
+        }
+    }
+
+    fn run_frame(&mut self, start: usize) {
+        let frame = &self.pending[start..start + DENOISE_FRAME];
+        for (bin, (sample, window)) in self.spectrum.iter_mut().zip(frame.iter().zip(&self.window)) {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.forward.process_with_scratch(&mut self.spectrum, &mut self.scratch);
+
+        // the first frames seed the noise estimate
+        let seeding = self.frames < 8;
+        self.frames += 1;
+        for k in 0..=DENOISE_FRAME / 2 {
+            let power = self.spectrum[k].norm_sqr();
+            let smoothed = &mut self.smoothed[k];
+            let noise = &mut self.noise[k];
+            if seeding {
+                *smoothed += (power - *smoothed) / self.frames as f32;
+                *noise = *smoothed;
+            } else {
+                *smoothed += (power - *smoothed) * 0.3;
+                // the minimum of the smoothed power is about half its mean for noise
+                *noise = (NOISE_BIAS * *smoothed).min(*noise * self.noise_rise);
+            }
+            let gain = if power > 0.0 { (1.0 - self.strength * *noise / power).max(self.floor) } else { self.floor };
+            self.spectrum[k] *= gain;
+            if k != 0 && k != DENOISE_FRAME / 2 {
+                self.spectrum[DENOISE_FRAME - k] *= gain;
+            }
+        }
+
+        self.inverse.process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        let scale = 1.0 / DENOISE_FRAME as f32;
+        for (out, (bin, window)) in self.overlap.iter_mut().zip(self.spectrum.iter().zip(&self.window)) {
+            *out += bin.re * scale * window;
+        }
+    }
+}
+
+impl AudioFilter for SpectralDenoiser {
+    fn process(&mut self, samples: &mut Vec<f32>) {
+        self.pending.extend_from_slice(samples);
+        self.received += samples.len() as u64;
+        samples.clear();
+        let mut start = 0;
+        while self.pending.len() - start >= DENOISE_FRAME {
+            self.run_frame(start);
+            start += DENOISE_HOP;
+            let done = &self.overlap[..DENOISE_HOP];
+            let skipped = self.skip.min(DENOISE_HOP);
+            self.skip -= skipped;
+            samples.extend_from_slice(&done[skipped..]);
+            self.overlap.copy_within(DENOISE_HOP.., 0);
+            self.overlap[DENOISE_FRAME - DENOISE_HOP..].fill(0.0);
+        }
+        self.pending.drain(..start);
+        self.emitted += samples.len() as u64;
+    }
+
+    fn flush(&mut self, samples: &mut Vec<f32>) {
+        let missing = (self.received - self.emitted) as usize;
+        let mut tail = vec![0.0; DENOISE_FRAME];
+        self.process(&mut tail);
+        tail.truncate(missing);
+        samples.extend(tail);
+    }
+}
+
+/// Audio preprocessing applied to each stream before it's transcribed, all off by default.
+/// The stages run in the order of the fields.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreprocessConfig {
+    /// Cutoff of a high-pass filter in Hz, removing DC offset and rumble. 80 suits speech.
+    pub high_pass: Option<f32>,
+    pub denoise: Option<DenoiseConfig>,
+    pub normalize: Option<NormalizeConfig>,
+    pub noise_gate: Option<NoiseGateConfig>,
+}
+
+impl PreprocessConfig {
+    /// Builds the filters for audio at `rate`.
+    pub fn chain(&self, rate: u32) -> FilterChain {
+        let mut chain = FilterChain::new();
+        if let Some(cutoff) = self.high_pass {
+            chain = chain.push(HighPass::new(cutoff, rate));
+        }
+        if let Some(denoise) = self.denoise {
+            chain = chain.push(SpectralDenoiser::new(denoise, rate));
+        }
+        if let Some(normalize) = self.normalize {
+            chain = chain.push(Normalizer::new(normalize, rate));
+        }
+        if let Some(gate) = self.noise_gate {
+            chain = chain.push(NoiseGate::new(gate, rate));
+        }
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16000;
+
+    fn sine(freq: f64, amplitude: f64, len: usize) -> Vec<f32> {
+        (0..len).map(|n| (amplitude * (2.0 * PI * freq * n as f64 / RATE as f64).sin()) as f32).collect()
+    }
+
+    // pseudo-random white noise, no rand dependency
+    fn noise(amplitude: f32, len: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+        }).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        (samples.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    // runs a filter in blocks of 1000 samples, as the decoder would
+    fn run<F: AudioFilter>(filter: &mut F, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        for block in input.chunks(1000) {
+            let mut block = block.to_vec();
+            filter.process(&mut block);
+            output.extend(block);
+        }
+        filter.flush(&mut output);
+        output
+    }
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let input: Vec<f32> = sine(440.0, 0.5, RATE as usize).iter().map(|x| x + 0.3).collect();
+        let output = run(&mut HighPass::new(80.0, RATE), &input);
+        let tail = &output[RATE as usize / 2..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 1e-3, "mean {}", mean);
+        assert!((rms(tail) - 0.5 / 2f64.sqrt()).abs() < 0.01);
+    }
+
+    #[test]
+    fn loudness_of_reference_sine() {
+        // a 1 kHz sine peaking at -20 dBFS measures -23 LUFS on a single channel; K-weighting
+        // is specified for 48 kHz, the same filter at 16 kHz reads a few tenths lower
+        let mut meter = LoudnessMeter::new(RATE);
+        for sample in sine(1000.0, db_to_gain(-20.0), RATE as usize * 5) {
+            meter.push(sample);
+        }
+        let loudness = meter.integrated().unwrap();
+        assert!((loudness - -23.0).abs() < 0.5, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn loudness_normalization_reaches_target() {
+        let config = NormalizeConfig { target: Normalization::Loudness(-23.0), max_gain_db: 30.0 };
+        let output = run(&mut Normalizer::new(config, RATE), &sine(1000.0, db_to_gain(-40.0), RATE as usize * 10));
+        let mut meter = LoudnessMeter::new(RATE);
+        for sample in &output[RATE as usize * 5..] {
+            meter.push(*sample);
+        }
+        let loudness = meter.integrated().unwrap();
+        assert!((loudness - -23.0).abs() < 0.5, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn peak_and_rms_normalization() {
+        let input = sine(440.0, 0.05, RATE as usize * 5);
+        let config = NormalizeConfig { target: Normalization::Peak(-1.0), max_gain_db: 30.0 };
+        let output = run(&mut Normalizer::new(config, RATE), &input);
+        let peak = output[RATE as usize * 4..].iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        assert!((peak as f64 - db_to_gain(-1.0)).abs() < 0.01, "peak {}", peak);
+
+        let config = NormalizeConfig { target: Normalization::Rms(-20.0), max_gain_db: 30.0 };
+        let output = run(&mut Normalizer::new(config, RATE), &input);
+        let level = 20.0 * rms(&output[RATE as usize * 4..]).log10();
+        assert!((level - -20.0).abs() < 0.2, "{} dBFS", level);
+    }
+
+    #[test]
+    fn normalization_gain_is_capped() {
+        let config = NormalizeConfig { target: Normalization::Rms(-20.0), max_gain_db: 10.0 };
+        let output = run(&mut Normalizer::new(config, RATE), &noise(0.001, RATE as usize * 2));
+        assert!(rms(&output) < 0.001 * db_to_gain(10.0) * 1.01);
+    }
+
+    #[test]
+    fn gate_attenuates_quiet_parts() {
+        let mut input = noise(0.001, RATE as usize);
+        input.extend(sine(440.0, 0.5, RATE as usize));
+        let output = run(&mut NoiseGate::new(NoiseGateConfig::default(), RATE), &input);
+        // quiet noise is taken down by the floor, the tone passes once the gate opened
+        assert!(rms(&output[..RATE as usize]) < rms(&input[..RATE as usize]) * 0.05);
+        let tone = RATE as usize + 100..;
+        assert!((rms(&output[tone.clone()]) - rms(&input[tone])).abs() < 0.01);
+    }
+
+    #[test]
+    fn denoiser_keeps_timing_and_length() {
+        let input = sine(440.0, 0.5, 12345);
+        let output = run(&mut SpectralDenoiser::new(DenoiseConfig { strength: 0.0, floor_db: 0.0 }, RATE), &input);
+        assert_eq!(output.len(), input.len());
+        // with nothing subtracted the overlap-add reconstructs the input
+        for (x, y) in input.iter().zip(&output) {
+            assert!((x - y).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn denoiser_suppresses_stationary_noise() {
+        let hiss = noise(0.05, RATE as usize * 4);
+        let output = run(&mut SpectralDenoiser::new(DenoiseConfig::default(), RATE), &hiss);
+        let second = RATE as usize..;
+        assert!(rms(&output[second.clone()]) < rms(&hiss[second]) * 0.5);
+
+        // a tone starting on top of the noise survives
+        let mut tone = vec![0.0; RATE as usize * 2];
+        tone.extend(sine(1000.0, 0.3, RATE as usize));
+        let mixed: Vec<f32> = hiss.iter().zip(tone.iter().chain(std::iter::repeat(&0.0))).map(|(n, t)| n + t).collect();
+        let output = run(&mut SpectralDenoiser::new(DenoiseConfig::default(), RATE), &mixed);
+        let during = RATE as usize * 9 / 4..RATE as usize * 11 / 4;
+        assert!((rms(&output[during.clone()]) - rms(&tone[during])).abs() < 0.03);
+    }
+
+    #[test]
+    fn chain_flushes_through_later_filters() {
+        let config = PreprocessConfig {
+            high_pass: Some(80.0),
+            denoise: Some(DenoiseConfig::default()),
+            normalize: Some(NormalizeConfig::default()),
+            noise_gate: Some(NoiseGateConfig::default()),
+        };
+        let input = sine(440.0, 0.1, 9999);
+        assert_eq!(run(&mut config.chain(RATE), &input).len(), input.len());
+        assert!(PreprocessConfig::default().chain(RATE).is_empty());
+    }
+}
@@ -9,6 +9,31 @@ pub enum WhisperError {
     IoError(#[from] std::io::Error),
     #[error("RbError: {0}")]
     RbError(#[from] RbError),
+    #[error("failed to load model: {0}")]
+    ModelLoad(String),
+    #[error("failed to decode audio: {0}")]
+    Decode(String),
+    #[error("no audio stream matches {0}")]
+    StreamNotFound(String),
+    #[error("audio capture failed: {0}")]
+    Capture(String),
+    #[error("failed to resample audio: {0}")]
+    Resample(String),
+    #[error("inference failed with code {0}")]
+    Inference(i32),
+    #[error("encoder failed with code {0}")]
+    Encoder(i32),
+    #[error("decoder failed with code {0}")]
+    Decoder(i32),
+    #[error("invalid decoder input: {0}")]
+    DecoderInput(String),
+    #[error("transcript receiver hung up")]
+    ChannelClosed,
+    #[error("transcription was cancelled")]
+    Cancelled,
+    #[error("LoggerError: {0}")]
+    LoggerError(#[from] log::SetLoggerError),
+    #[cfg(feature = "ffmpeg")]
     #[error("FFmpegError: {0}")]
     FFmpegError(#[from] ffmpeg_next::Error),
 }
\ No newline at end of file
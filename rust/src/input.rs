@@ -0,0 +1,87 @@
+use std::fmt;
+use std::io::{Read, Seek};
+#[cfg(feature = "capture")]
+use crate::capture::CaptureSource;
+use crate::pcm::PcmSpec;
+
+/// A seekable byte source, e.g. a `File` or a `Cursor`.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Where `process_audio` reads the encoded audio from.
+pub enum AudioInput {
+    /// A file path or anything else ffmpeg can open by name.
+    Path(String),
+    /// A complete file held in memory, e.g. an HTTP request body.
+    Bytes(Vec<u8>),
+    /// A seekable source. Containers that need seeking (e.g. MP4 with the index at the end) work.
+    Reader(Box<dyn ReadSeek>),
+    /// A forward-only source, e.g. an object store download stream.
+    Stream(Box<dyn Read + Send>),
+    /// Headerless PCM in the declared layout, decoded without ffmpeg.
+    Pcm(PcmSpec, Box<dyn Read + Send>),
+    /// A microphone or other capture device, captured in real time.
+    #[cfg(feature = "capture")]
+    Capture(CaptureSource),
+}
+
+/// Which audio stream of a container to transcribe.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StreamSelector {
+    /// The stream ffmpeg considers the best audio stream.
+    #[default]
+    Best,
+    /// The stream with this index in the container, counting all streams.
+    Index(usize),
+    /// The first audio stream with this `language` tag, either an ISO 639-2 or a whisper code.
+    Language(String),
+    /// The first audio stream whose `title` tag contains this, ignoring case.
+    Title(String),
+}
+
+impl AudioInput {
+    pub fn reader<R: Read + Seek + Send + 'static>(reader: R) -> Self {
+        AudioInput::Reader(Box::new(reader))
+    }
+
+    pub fn stream<R: Read + Send + 'static>(stream: R) -> Self {
+        AudioInput::Stream(Box::new(stream))
+    }
+
+    pub fn pcm<R: Read + Send + 'static>(spec: PcmSpec, source: R) -> Self {
+        AudioInput::Pcm(spec, Box::new(source))
+    }
+}
+
+impl From<String> for AudioInput {
+    fn from(path: String) -> Self {
+        AudioInput::Path(path)
+    }
+}
+
+impl From<&str> for AudioInput {
+    fn from(path: &str) -> Self {
+        AudioInput::Path(path.to_string())
+    }
+}
+
+impl From<Vec<u8>> for AudioInput {
+    fn from(bytes: Vec<u8>) -> Self {
+        AudioInput::Bytes(bytes)
+    }
+}
+
+impl fmt::Debug for AudioInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioInput::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            AudioInput::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            AudioInput::Reader(_) => f.write_str("Reader"),
+            AudioInput::Stream(_) => f.write_str("Stream"),
+            AudioInput::Pcm(spec, _) => f.debug_tuple("Pcm").field(spec).finish(),
+            #[cfg(feature = "capture")]
+            AudioInput::Capture(source) => f.debug_tuple("Capture").field(source).finish(),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/// Language whisper should transcribe in.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LanguageHint {
+    /// Let whisper detect the language.
+    #[default]
+    Auto,
+    /// A whisper language code like `en`, or an ISO 639-2 code like `eng`.
+    Code(String),
+    /// Use the `language` tag of the selected audio stream, detect it if there is none.
+    FromStream,
+}
+
+impl LanguageHint {
+    /// The whisper language code to use, `auto` for detection.
+    pub(crate) fn resolve(&self, stream_language: Option<&str>) -> &'static str {
+        let code = match self {
+            LanguageHint::Auto => return "auto",
+            LanguageHint::Code(code) => Some(code.as_str()),
+            LanguageHint::FromStream => stream_language,
+        };
+        match code {
+            Some(code) => whisper_language(code).unwrap_or_else(|| {
+                log::warn!("unknown language {:?}, detecting it instead", code);
+                "auto"
+            }),
+            None => "auto",
+        }
+    }
+}
+
+// ISO 639-2 codes, both bibliographic and terminology forms, of the languages whisper knows
+const ISO_639_2: &[(&str, &str)] = &[
+    ("afr", "af"), ("alb", "sq"), ("sqi", "sq"), ("amh", "am"), ("ara", "ar"), ("arm", "hy"),
+    ("hye", "hy"), ("asm", "as"), ("aze", "az"), ("bak", "ba"), ("baq", "eu"), ("eus", "eu"),
+    ("bel", "be"), ("ben", "bn"), ("bos", "bs"), ("bre", "br"), ("bul", "bg"), ("bur", "my"),
+    ("mya", "my"), ("cat", "ca"), ("chi", "zh"), ("zho", "zh"), ("cze", "cs"), ("ces", "cs"),
+    ("dan", "da"), ("dut", "nl"), ("nld", "nl"), ("eng", "en"), ("est", "et"), ("fao", "fo"),
+    ("fin", "fi"), ("fre", "fr"), ("fra", "fr"), ("geo", "ka"), ("kat", "ka"), ("ger", "de"),
+    ("deu", "de"), ("glg", "gl"), ("gre", "el"), ("ell", "el"), ("guj", "gu"), ("hat", "ht"),
+    ("hau", "ha"), ("haw", "haw"), ("heb", "he"), ("hin", "hi"), ("hrv", "hr"), ("hun", "hu"),
+    ("ice", "is"), ("isl", "is"), ("ind", "id"), ("ita", "it"), ("jav", "jw"), ("jpn", "ja"),
+    ("kan", "kn"), ("kaz", "kk"), ("khm", "km"), ("kor", "ko"), ("lao", "lo"), ("lat", "la"),
+    ("lav", "lv"), ("lin", "ln"), ("lit", "lt"), ("ltz", "lb"), ("mac", "mk"), ("mkd", "mk"),
+    ("mlg", "mg"), ("mal", "ml"), ("mao", "mi"), ("mri", "mi"), ("mar", "mr"), ("may", "ms"),
+    ("msa", "ms"), ("mlt", "mt"), ("mon", "mn"), ("nep", "ne"), ("nno", "nn"), ("nor", "no"),
+    ("nob", "no"), ("oci", "oc"), ("pan", "pa"), ("per", "fa"), ("fas", "fa"), ("pol", "pl"),
+    ("por", "pt"), ("pus", "ps"), ("rum", "ro"), ("ron", "ro"), ("rus", "ru"), ("san", "sa"),
+    ("sin", "si"), ("slo", "sk"), ("slk", "sk"), ("slv", "sl"), ("sna", "sn"), ("snd", "sd"),
+    ("som", "so"), ("spa", "es"), ("srp", "sr"), ("sun", "su"), ("swa", "sw"), ("swe", "sv"),
+    ("tam", "ta"), ("tat", "tt"), ("tel", "te"), ("tgk", "tg"), ("tgl", "tl"), ("tha", "th"),
+    ("tib", "bo"), ("bod", "bo"), ("tuk", "tk"), ("tur", "tr"), ("ukr", "uk"), ("urd", "ur"),
+    ("uzb", "uz"), ("vie", "vi"), ("wel", "cy"), ("cym", "cy"), ("yid", "yi"), ("yor", "yo"),
+    ("yue", "yue"),
+];
+
+/// Maps a container language tag to a whisper language code.
+///
+/// Accepts ISO 639-2 codes, as found in MKV and MP4 metadata, and codes whisper
+/// already knows, which are passed through. Returns `None` for `und` and unknown codes.
+pub fn whisper_language(code: &str) -> Option<&'static str> {
+    let code = code.trim().to_ascii_lowercase();
+    ISO_639_2.iter()
+        .find(|(iso, whisper)| *iso == code || *whisper == code)
+        .map(|(_, whisper)| *whisper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso_639_codes() {
+        // ISO 639-1, which are whisper's codes
+        assert_eq!(whisper_language("en"), Some("en"));
+        assert_eq!(whisper_language("DE"), Some("de"));
+        // ISO 639-2, bibliographic and terminology forms
+        assert_eq!(whisper_language("eng"), Some("en"));
+        assert_eq!(whisper_language("ger"), Some("de"));
+        assert_eq!(whisper_language("deu"), Some("de"));
+        assert_eq!(whisper_language(" Chi "), Some("zh"));
+        // whisper's own three letter codes
+        assert_eq!(whisper_language("haw"), Some("haw"));
+        assert_eq!(whisper_language("yue"), Some("yue"));
+    }
+
+    #[test]
+    fn unknown_tags() {
+        for tag in ["und", "zxx", "", "xx", "en-US", "english"] {
+            assert_eq!(whisper_language(tag), None, "{:?}", tag);
+        }
+    }
+
+    #[test]
+    fn hints() {
+        assert_eq!(LanguageHint::Auto.resolve(Some("eng")), "auto");
+        assert_eq!(LanguageHint::Code("fra".to_string()).resolve(Some("eng")), "fr");
+        assert_eq!(LanguageHint::Code("klingon".to_string()).resolve(None), "auto");
+        assert_eq!(LanguageHint::FromStream.resolve(Some("jpn")), "ja");
+        assert_eq!(LanguageHint::FromStream.resolve(Some("und")), "auto");
+        assert_eq!(LanguageHint::FromStream.resolve(None), "auto");
+    }
+}
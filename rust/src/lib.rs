@@ -1,23 +1,121 @@
 mod accel;
+#[cfg(feature = "capture")]
+mod capture;
+mod channels;
+mod clock;
+#[cfg(feature = "ffmpeg")]
 mod audio;
+#[cfg(feature = "ffmpeg")]
+mod avio;
+mod config;
+mod dsp;
 mod errors;
+mod input;
+mod language;
+mod live;
+mod logging;
+mod mel;
+mod model;
+mod pcm;
+mod progress;
 mod rb;
+mod resample;
+mod transcript;
+mod wav;
 
-use std::io::Write;
-use rb::{Producer, Consumer, SpscRb};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+use std::sync::mpsc;
+#[cfg(feature = "ffmpeg")]
 use crate::audio::process_audio;
-use crate::rb::{RB, RbConsumer, SampleRange};
+use crate::channels::{OpenedStreams, StreamOpener};
+use crate::pcm::process_pcm;
+#[cfg(not(feature = "ffmpeg"))]
+use crate::wav::process_wav;
+use crate::logging::{install_native_logging, whisper_log};
+use crate::rb::{RbConsumer, RbError};
+
+pub use crate::accel::{convert_f32_to_pcm16, Dither};
+#[cfg(feature = "capture")]
+pub use crate::capture::{capture_devices, CaptureDevice, CaptureSource};
+pub use crate::channels::ChannelPolicy;
+pub use crate::clock::{SampleClock, Timeline, TimelineMark};
+pub use crate::config::{CancelToken, LiveConfig, SessionConfig};
+pub use crate::dsp::{AudioFilter, DenoiseConfig, FilterChain, HighPass, NoiseGate, NoiseGateConfig, NormalizeConfig,
+                     Normalization, Normalizer, PreprocessConfig, SpectralDenoiser};
+pub use crate::errors::WhisperError;
+pub use crate::input::{AudioInput, ReadSeek, StreamSelector};
+pub use crate::language::{whisper_language, LanguageHint};
+pub use crate::logging::{init_logging, LogFormat, LoggingConfig, NATIVE_LOG_TARGET};
+pub use crate::mel::{mel_filters, MelChunk, MelSpectrogram};
+pub use crate::model::{Model, SpecialToken, Token};
+pub use crate::pcm::{alaw_to_linear, mulaw_to_linear, PcmFormat, PcmSpec};
+pub use crate::progress::{ProgressEvent, ProgressReporter};
+pub use crate::rb::OverflowPolicy;
+pub use crate::resample::{ResampleQuality, ResamplerBackend, SincResampler};
+pub use crate::transcript::{FailurePolicy, Segment, SkippedChunk, Transcript};
+pub use crate::wav::{read_header as read_wav_header, WavHeader};
+
+/// Ring buffer internals, public only for the benchmarks under `rust/benches`.
+#[doc(hidden)]
+pub mod bench_support {
+    pub use crate::accel::{convert_pcm16_to_f32, supported_kernels, Kernels, Sample};
+    pub use crate::rb::{Consumer, Producer, RbConsumer, RbError, RbInspector, RbProducer, ReadView, Result, SpmcRb, SpscRb, RB};
+}
+
+const VAD_FRAME_SIZE: usize = 16000;
 
 #[cxx::bridge(namespace = "WhisperRust")]
 mod ffi {
 
+    /// Per-run decoding options for `infer_mel`.
+    struct InferOptions {
+        /// Use the cheaper fallback decoding parameters.
+        fallback: bool,
+        /// Let whisper predict segment timestamps.
+        timestamps: bool,
+        /// Whisper language code, `auto` to detect it.
+        language: String,
+    }
+
+    /// Audio missing from the transcript `run_transcript` returns, see `SkippedChunk`.
+    struct SkippedRange {
+        channel: usize,
+        start_ms: u64,
+        end_ms: u64,
+        code: i32,
+    }
+
+    /// Special tokens looked up by `special_token`.
+    enum SpecialToken {
+        EndOfText,
+        StartOfTranscript,
+        StartOfLm,
+        Previous,
+        NoSpeech,
+        NoTimestamps,
+        TimestampBegin,
+        Translate,
+        Transcribe,
+    }
+
     extern "Rust" {
 
         type SenderWrapper;
 
-        fn send_text(sender: &SenderWrapper, text: String);
+        fn send_text(sender: &SenderWrapper, text: String, t0: i64, t1: i64);
+
+        fn is_aborted(sender: &SenderWrapper) -> bool;
+
+        fn send_progress(sender: &SenderWrapper, progress: i32);
 
-        fn run_transcript(audio_file: String);
+        fn send_new_segments(sender: &SenderWrapper, n_new: i32, n_segments: i32);
+
+        fn whisper_log(level: i32, text: &[u8]);
+
+        fn run_transcript(audio_file: String) -> Result<Vec<SkippedRange>>;
+
+        fn init_default_logging() -> Result<()>;
     }
 
     unsafe extern "C++" {
@@ -25,114 +123,467 @@ mod ffi {
 
         type WhisperWrapper;
 
-        pub unsafe fn infer_buffer(&self, sender: &SenderWrapper, buffer: *const f32, buffer_size: usize) -> i32;
-        pub unsafe fn get_segment_count(&self) -> i32;
-        pub unsafe fn create_whisper_wrapper(model_path: &str) -> UniquePtr<WhisperWrapper>;
+        pub unsafe fn infer_mel(&self, sender: &SenderWrapper, mel: *const f32, n_len: usize, n_audio: usize, options: &InferOptions) -> i32;
+        pub unsafe fn n_mels(&self) -> i32;
+        pub unsafe fn encode(&self, mel: *const f32, n_len: usize, offset: i32, n_threads: i32) -> i32;
+        pub unsafe fn decode(&self, tokens: &[i32], n_past: i32, n_threads: i32) -> i32;
+        /// Logits of the last token of the last successful `decode`, empty if there is none.
+        pub unsafe fn logits(&self) -> &[f32];
+        pub unsafe fn tokenize(&self, text: &str) -> Vec<i32>;
+        pub unsafe fn token_bytes(&self, token: i32) -> &[u8];
+        pub unsafe fn special_token(&self, token: SpecialToken) -> i32;
+        /// -1 for an unknown language.
+        pub unsafe fn lang_token(&self, lang: &str) -> i32;
+        pub unsafe fn n_vocab(&self) -> i32;
+        pub unsafe fn n_text_ctx(&self) -> i32;
+        pub unsafe fn is_multilingual(&self) -> bool;
+        pub unsafe fn create_whisper_wrapper(model_path: &str) -> Result<UniquePtr<WhisperWrapper>>;
+        pub unsafe fn install_whisper_log_callback();
     }
 }
 
 pub struct SenderWrapper {
-    sender: std::sync::mpsc::SyncSender<String>,
+    sender: mpsc::SyncSender<Segment>,
+    progress: ProgressReporter,
+    // start of the decoded audio in the input
+    offset: Duration,
+    // stream index, and (start, len) in samples of the chunk currently being inferred
+    channel: Cell<usize>,
+    chunk: Cell<(usize, usize)>,
+    // source timestamps of the chunk, for inputs which have them
+    timeline: RefCell<Timeline>,
+    segments_total: Cell<usize>,
+    cancel: CancelToken,
+    // set once the text receiver hung up
+    closed: Cell<bool>,
 }
 
 impl SenderWrapper {
-    pub fn new(sender: std::sync::mpsc::SyncSender<String>) -> Self {
-        Self { sender }
+    pub fn new(sender: mpsc::SyncSender<Segment>, progress: ProgressReporter, cancel: CancelToken) -> Self {
+        Self {
+            sender,
+            progress,
+            offset: Duration::ZERO,
+            channel: Cell::new(0),
+            chunk: Cell::new((0, 0)),
+            timeline: RefCell::new(Timeline::default()),
+            segments_total: Cell::new(0),
+            cancel,
+            closed: Cell::new(false),
+        }
+    }
+
+    fn set_chunk(&self, channel: usize, start: usize, len: usize, timeline: Timeline) {
+        self.channel.set(channel);
+        self.chunk.set((start, len));
+        self.timeline.replace(timeline);
     }
+
+    /// Time of the sample at `pos` in the input, following its timestamps when known.
+    fn source_time(&self, pos: usize) -> Duration {
+        self.timeline.borrow().source_time(pos)
+            .unwrap_or_else(|| self.offset + samples_to_duration(pos))
+    }
+
+    fn check(&self) -> Result<(), WhisperError> {
+        if self.cancel.is_cancelled() {
+            return Err(WhisperError::Cancelled);
+        }
+        if self.closed.get() {
+            return Err(WhisperError::ChannelClosed);
+        }
+        Ok(())
+    }
+}
+
+/// `t0` and `t1` are in 10 ms units from the start of the current chunk.
+pub fn send_text(sender: &SenderWrapper, text: String, t0: i64, t1: i64) {
+    let chunk_start = sender.chunk.get().0;
+    let segment = Segment {
+        channel: sender.channel.get(),
+        speaker: None,
+        start: sender.source_time(chunk_start + SampleClock::WHISPER.position_of_centis(t0)),
+        end: sender.source_time(chunk_start + SampleClock::WHISPER.position_of_centis(t1)),
+        text,
+    };
+    if sender.sender.send(segment).is_err() {
+        // can't unwind through whisper_full, stop it through `is_aborted` instead
+        sender.closed.set(true);
+    }
+}
+
+pub fn is_aborted(sender: &SenderWrapper) -> bool {
+    sender.check().is_err()
+}
+
+pub fn send_progress(sender: &SenderWrapper, progress: i32) {
+    let (start, len) = sender.chunk.get();
+    sender.progress.report(ProgressEvent::Inference {
+        // on the same timeline as the segments
+        chunk_start: sender.source_time(start),
+        chunk_len: samples_to_duration(len),
+        percent: progress,
+    });
+}
+
+pub fn send_new_segments(sender: &SenderWrapper, n_new: i32, _n_segments: i32) {
+    let count = n_new.max(0) as usize;
+    let total = sender.segments_total.get() + count;
+    sender.segments_total.set(total);
+    sender.progress.report(ProgressEvent::Segments { count, total });
 }
 
-pub fn send_text(sender: &SenderWrapper, text: String) {
-    sender.sender.send(text).unwrap();
+fn samples_to_duration(samples: usize) -> Duration {
+    SampleClock::WHISPER.time(samples)
 }
 
 
-pub fn run_transcript(audio_file: String) {
-    let logger_name = "rust_wrapper";
-    env_logger::builder()
-        .format(move |buf, record| {
-            writeln!(buf, "[{}][{}]<{}> - {}",
-                     &logger_name,
-                     chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                     record.level(), record.args())
-        })
-        .init();
-    println!("Hello from Rust!");
+/// Installs the `[rust_wrapper]` formatted logger, for C++ hosts without a Rust logger.
+pub fn init_default_logging() -> Result<(), WhisperError> {
+    init_logging(LoggingConfig::default())
+}
+
+/// Transcribes `audio_file` for C++ callers, returns the ranges missing from the transcript.
+pub fn run_transcript(audio_file: String) -> Result<Vec<ffi::SkippedRange>, WhisperError> {
+    let transcript = transcribe(audio_file.into(), SessionConfig::default())?;
+    Ok(transcript.skipped_chunks.iter().map(|chunk| {
+        log::warn!("no transcript for {:?} - {:?}, inference failed with code {}",
+                   chunk.start, chunk.end, chunk.code);
+        ffi::SkippedRange {
+            channel: chunk.channel,
+            start_ms: chunk.start.as_millis() as u64,
+            end_ms: chunk.end.as_millis() as u64,
+            code: chunk.code,
+        }
+    }).collect())
+}
+
+/// Transcribes `input`, reporting progress to `config.progress`.
+///
+/// Returns the first error hit by either the decoding or the inference side.
+pub fn transcribe(input: AudioInput, config: SessionConfig) -> Result<Transcript, WhisperError> {
+    install_native_logging();
 
-    let rb_obj = SpscRb::new(16000*120);
-    let prod = rb_obj.producer();
-    let cons = rb_obj.consumer();
+    // the decoder creates one ring buffer per transcribed stream once it knows the channels
+    let (cons_tx, cons_rx) = mpsc::channel();
+    let opener = StreamOpener::new(16000*120, cons_tx);
 
-    let (text_tx, text_rx) = std::sync::mpsc::sync_channel(10);
+    let (text_tx, text_rx) = mpsc::sync_channel(10);
 
+    let decode_config = config.clone();
     let t1 = std::thread::spawn(move || {
-        match process_audio(audio_file, prod) {
+        let ret = decode_audio(input, opener, &decode_config);
+        match &ret {
             Ok(_) => log::info!("Audio processed successfully!"),
-            Err(e) => log::error!("Error processing audio: {}", e.to_string()),
-
+            Err(e) => log::error!("Error processing audio: {}", e),
         };
+        ret
     });
 
+    let speakers = config.speakers.clone();
+    let progress = config.progress.clone();
+    let live = config.live.is_some();
     let t2 = std::thread::spawn(move || {
-        let ww = unsafe {
-            ffi::create_whisper_wrapper(
-                "/media/msd/models/ggml-large-v3-q5_0.bin")
-        };
-        let mut bufferf32: Vec<f32> = vec![0.0; 16000*120];
-        let mut global_pos = 0usize;
-        let VAD_FRAME_SIZE = 16000;
-        let sender_wrapper = SenderWrapper::new(text_tx);
-        loop {
-            match cons.peek_blocking(global_pos, &mut bufferf32[..VAD_FRAME_SIZE*3]) {
-
-                Ok(sample_range) => {
-                    match sample_range {
-                        SampleRange::Adjacent(buf, buf_size) => {
-                            global_pos += buf_size;
-                            log::info!("Received {} samples", buf_size);
-                            //(unsafe{ offline_stream_engine.vad_infer_buffer(buf, buf_size, false)}, false )
-                            let ret = unsafe{ww.infer_buffer(&sender_wrapper, buf, buf_size)};
-                            log::info!("Processed {} samples: ret: {}", buf_size, ret);
-                            ()
-                        }
-                        SampleRange::NonAdjacent(buf_size) => {
-                            global_pos += buf_size;
-                            //(unsafe{ offline_stream_engine.vad_infer_buffer(bufferf32[..buf_size].as_ptr(), buf_size, false)}, false)
-                            log::info!("Received {} samples", buf_size)
-                        }
-                        SampleRange::EofEmpty => panic!("Unexpected EOF"),
-                    }
-                },
-                Err(rb::RbError::EOF(sample_range)) => {
-                    match sample_range {
-                        SampleRange::Adjacent(buf, buf_size) => {
-                            global_pos += buf_size;
-                            if buf_size % 800 != 0 {
-                                //(unsafe{ offline_stream_engine.vad_infer_buffer(buf, buf_size - (buf_size % 800), true)}, true)
-                                log::info!("Received {} samples", buf_size)
-                            } else {
-                                //(unsafe{ offline_stream_engine.vad_infer_buffer(buf, buf_size, true)}, true)
-                                log::info!("Received {} samples", buf_size)
-                            }
-                        }
-                        SampleRange::NonAdjacent(buf_size) => {
-                            global_pos += buf_size;
-                            //(unsafe{ offline_stream_engine.vad_infer_buffer(bufferf32[..buf_size].as_ptr(), buf_size, true)}, true)
-                            log::info!("Received {} samples", buf_size)
-                        }
-                        //SampleRange::EofEmpty=> (vec![], true),
-                        SampleRange::EofEmpty=> (),
-                    }
+        let mut sender_wrapper = SenderWrapper::new(text_tx, config.progress.clone(), config.cancel.clone());
+        sender_wrapper.offset = config.start.unwrap_or_default();
+        let ret = run_inference(cons_rx, &config, &sender_wrapper);
+        if let Err(e) = &ret {
+            log::error!("Error running inference: {}", e);
+        }
+        ret
+    });
+
+    let mut transcript = collect_segments(text_rx, &speakers, &progress, live);
+    let decode_ret = t1.join()
+        .map_err(|_| anyhow::anyhow!("audio decoding thread panicked"))?;
+    let infer_ret = t2.join()
+        .map_err(|_| anyhow::anyhow!("inference thread panicked"))?;
+    // a failed inference closes the ring buffer, which in turn fails the decoder,
+    // so the inference error is the root cause
+    transcript.skipped_chunks = infer_ret?;
+    decode_ret?;
+    Ok(transcript)
+}
+
+/// Labels the segments whisper emits with their speaker and reports them, then merges
+/// the streams by time. A live session never ends, its segments are only reported.
+fn collect_segments(text_rx: mpsc::Receiver<Segment>, speakers: &[String], progress: &ProgressReporter,
+                    live: bool) -> Transcript {
+    let mut transcript = Transcript::default();
+    for mut segment in text_rx {
+        log::info!("Received text: {}", segment.text);
+        // whisper sends a bare newline before the first segment of each chunk
+        if segment.text.trim().is_empty() {
+            continue;
+        }
+        segment.speaker = speakers.get(segment.channel).cloned();
+        progress.report(ProgressEvent::Segment(segment.clone()));
+        if !live {
+            transcript.segments.push(segment);
+        }
+    }
+    // stable, so this merges the per-channel sequences, which are each in time order
+    transcript.segments.sort_by_key(|segment| segment.start);
+    transcript.text = transcript.segments.iter().map(|segment| segment.text.clone()).collect();
+    transcript
+}
+
+/// Decodes `input` into the ring buffers. Raw PCM is decoded in Rust, everything else
+/// through ffmpeg, or by the built-in WAV reader when built without ffmpeg.
+fn decode_audio(input: AudioInput, opener: StreamOpener, config: &SessionConfig) -> Result<(), WhisperError> {
+    #[cfg(not(feature = "ffmpeg"))]
+    if config.live.is_some() {
+        return Err(WhisperError::Decode("live input needs the ffmpeg feature".to_string()));
+    }
+    match input {
+        AudioInput::Pcm(spec, source) => process_pcm(source, spec, opener, config),
+        #[cfg(feature = "capture")]
+        AudioInput::Capture(source) => capture::process_capture(source, opener, config),
+        #[cfg(feature = "ffmpeg")]
+        input => process_audio(input, opener, config),
+        #[cfg(not(feature = "ffmpeg"))]
+        AudioInput::Path(path) => process_wav(std::io::BufReader::new(std::fs::File::open(path)?), opener, config),
+        #[cfg(not(feature = "ffmpeg"))]
+        AudioInput::Bytes(bytes) => process_wav(std::io::Cursor::new(bytes), opener, config),
+        #[cfg(not(feature = "ffmpeg"))]
+        AudioInput::Reader(reader) => process_wav(reader, opener, config),
+        #[cfg(not(feature = "ffmpeg"))]
+        AudioInput::Stream(stream) => process_wav(stream, opener, config),
+    }
+}
+
+/// Transcribes the streams the decoder sends through `consumers`, taking one chunk
+/// from each in turn so the decoder, which writes them in lockstep, never blocks on a full buffer.
+fn run_inference(opened: mpsc::Receiver<OpenedStreams>, config: &SessionConfig,
+                 sender_wrapper: &SenderWrapper) -> Result<Vec<SkippedChunk>, WhisperError> {
+    let ww = unsafe { ffi::create_whisper_wrapper(&config.model_path) }
+        .map_err(|e| WhisperError::ModelLoad(e.to_string()))?;
+    let (mut consumers, stream_language) = match opened.recv() {
+        Ok(opened) => (opened.consumers, opened.language),
+        // the decoder failed before opening the streams and reports why
+        Err(_) => return Ok(Vec::new()),
+    };
+    let language = config.language.resolve(stream_language.as_deref());
+    log::info!("transcribing {} stream(s), language: {}", consumers.len(), language);
+    // chunks wrapping around the end of a ring buffer are copied here, whisper needs them in one piece
+    let mut scratch: Vec<f32> = Vec::with_capacity(VAD_FRAME_SIZE*3);
+    let mut positions = vec![0usize; consumers.len()];
+    // spectrogram of each stream, computed once as it is read
+    let n_mel = unsafe { ww.n_mels() } as usize;
+    let mut mels: Vec<MelSpectrogram> = (0..consumers.len()).map(|_| MelSpectrogram::new(n_mel)).collect();
+    let mut finished = vec![false; consumers.len()];
+    let mut skipped_chunks = Vec::new();
+    // separately transcribed channels are merged by segment time
+    let timestamps = config.channels == ChannelPolicy::Separate;
+    while finished.contains(&false) {
+        for (channel, cons) in consumers.iter_mut().enumerate() {
+            if finished[channel] {
+                continue;
+            }
+            sender_wrapper.check()?;
+            let global_pos = positions[channel];
+            let view = match cons.peek_blocking(global_pos, VAD_FRAME_SIZE*3) {
+                Ok(view) => view,
+                Err(RbError::EOF) => {
+                    finished[channel] = true;
+                    continue;
+                }
+                // the live input overflowed, go on with what's left
+                Err(RbError::Dropped { pos, resume }) => {
+                    log::warn!("transcription fell behind, skipped {:?} of audio",
+                               samples_to_duration(resume - pos));
+                    positions[channel] = resume;
+                    continue;
                 }
-                _ => panic!("Unexpected rb error"),
+                Err(e) => return Err(e.into()),
+            };
+            let samples = view.contiguous(&mut scratch);
+            log::info!("Received {} samples", samples.len());
+            let mel = &mut mels[channel];
+            if mel.position() != global_pos {
+                mel.restart(global_pos);
             }
+            mel.push(samples);
+            if view.is_eof() {
+                mel.finish();
+            }
+            let chunk = mel.chunk(global_pos, samples.len())?;
+            sender_wrapper.set_chunk(channel, global_pos, samples.len(), view.timeline());
+            let options = ffi::InferOptions { fallback: false, timestamps, language: language.to_string() };
+            let run = |options: &ffi::InferOptions| run_whisper(&ww, sender_wrapper, &chunk, options);
+            if let Some(skipped) = infer_chunk(sender_wrapper, config.failure_policy, options, run)? {
+                skipped_chunks.push(skipped);
+            }
+            finished[channel] = view.is_eof();
+            positions[channel] = view.commit();
+            mels[channel].discard(positions[channel]);
         }
-    });
+    }
+    Ok(skipped_chunks)
+}
 
+/// Runs whisper on the chunk set in `sender_wrapper` through `run`, applying `policy` if it fails.
+/// Returns the chunk as skipped if the policy decided to go on without it.
+fn infer_chunk<F>(sender_wrapper: &SenderWrapper, policy: FailurePolicy, mut options: ffi::InferOptions,
+                  mut run: F) -> Result<Option<SkippedChunk>, WhisperError>
+    where F: FnMut(&ffi::InferOptions) -> Result<i32, WhisperError>
+{
+    let channel = sender_wrapper.channel.get();
+    let (start, len) = sender_wrapper.chunk.get();
+    let mut ret = run(&options)?;
+    if ret != 0 && policy == FailurePolicy::RetryWithFallbackParams {
+        log::warn!("inference failed with code {} at sample {}, retrying with fallback params", ret, start);
+        options.fallback = true;
+        ret = run(&options)?;
+    }
+    if ret == 0 {
+        return Ok(None);
+    }
+    match policy {
+        FailurePolicy::Abort => Err(WhisperError::Inference(ret)),
+        FailurePolicy::SkipChunk | FailurePolicy::RetryWithFallbackParams => {
+            log::warn!("inference failed with code {} at sample {}, skipping {} samples", ret, start, len);
+            Ok(Some(SkippedChunk {
+                channel,
+                start: sender_wrapper.source_time(start),
+                end: sender_wrapper.source_time(start + len),
+                code: ret,
+            }))
+        }
+    }
+}
 
+fn run_whisper(ww: &ffi::WhisperWrapper, sender_wrapper: &SenderWrapper,
+               mel: &MelChunk, options: &ffi::InferOptions) -> Result<i32, WhisperError> {
+    let ret = unsafe { ww.infer_mel(sender_wrapper, mel.data.as_ptr(), mel.n_len, mel.n_audio, options) };
+    log::info!("Processed {} mel frames: ret: {}", mel.n_audio, ret);
+    // an abort requested through `is_aborted` makes whisper_full fail, report the reason instead
+    sender_wrapper.check()?;
+    Ok(ret)
+}
 
-    for text in text_rx {
-        log::info!("Received text: {}", text);
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::{collect_segments, ffi, infer_chunk, send_progress, CancelToken, FailurePolicy, ProgressEvent,
+                ProgressReporter, Segment, SenderWrapper, SkippedChunk, Timeline, WhisperError};
+
+    fn sender() -> SenderWrapper {
+        let (text_tx, _text_rx) = mpsc::sync_channel(1);
+        let mut sender = SenderWrapper::new(text_tx, ProgressReporter::disabled(), CancelToken::new());
+        sender.offset = Duration::from_secs(10);
+        sender.set_chunk(1, 16000, 48000, Timeline::default());
+        sender
+    }
+
+    fn options() -> ffi::InferOptions {
+        ffi::InferOptions { fallback: false, timestamps: false, language: "en".to_string() }
+    }
+
+    // whisper_full fails with `codes`, one per run, and the options of each run are recorded
+    fn infer(policy: FailurePolicy, codes: &[i32]) -> (Result<Option<SkippedChunk>, WhisperError>, Vec<bool>) {
+        let mut runs = Vec::new();
+        let ret = infer_chunk(&sender(), policy, options(), |options| {
+            runs.push(options.fallback);
+            Ok(codes[runs.len() - 1])
+        });
+        (ret, runs)
+    }
+
+    #[test]
+    fn abort_fails_the_session() {
+        let (ret, runs) = infer(FailurePolicy::Abort, &[-6]);
+        assert!(matches!(ret, Err(WhisperError::Inference(-6))));
+        assert_eq!(runs, vec![false]);
+        assert!(matches!(infer(FailurePolicy::Abort, &[0]).0, Ok(None)));
+    }
+
+    #[test]
+    fn skip_chunk_reports_the_gap() {
+        let (ret, runs) = infer(FailurePolicy::SkipChunk, &[-6]);
+        assert_eq!(ret.unwrap(), Some(SkippedChunk {
+            channel: 1,
+            start: Duration::from_secs(11),
+            end: Duration::from_secs(14),
+            code: -6,
+        }));
+        assert_eq!(runs, vec![false]);
+    }
+
+    #[test]
+    fn retry_uses_the_fallback_params() {
+        let (ret, runs) = infer(FailurePolicy::RetryWithFallbackParams, &[-6, 0]);
+        assert_eq!(ret.unwrap(), None);
+        assert_eq!(runs, vec![false, true]);
+        let (ret, runs) = infer(FailurePolicy::RetryWithFallbackParams, &[-6, -7]);
+        assert_eq!(ret.unwrap().map(|skipped| skipped.code), Some(-7));
+        assert_eq!(runs, vec![false, true]);
     }
-    t1.join().unwrap();
-    t2.join().unwrap();
-}
\ No newline at end of file
+
+    #[test]
+    fn progress_follows_the_trim_offset() {
+        let (text_tx, _text_rx) = mpsc::sync_channel(1);
+        let (progress_tx, progress_rx) = mpsc::channel();
+        let mut sender = SenderWrapper::new(text_tx, ProgressReporter::from_sender(progress_tx), CancelToken::new());
+        sender.offset = Duration::from_secs(60);
+        sender.set_chunk(0, 16000, 48000, Timeline::default());
+        send_progress(&sender, 50);
+        assert_eq!(progress_rx.try_recv().unwrap(), ProgressEvent::Inference {
+            chunk_start: Duration::from_secs(61),
+            chunk_len: Duration::from_secs(3),
+            percent: 50,
+        });
+    }
+
+    fn segment(channel: usize, start_ms: u64, text: &str) -> Segment {
+        Segment {
+            channel,
+            speaker: None,
+            start: Duration::from_millis(start_ms),
+            end: Duration::from_millis(start_ms + 900),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn streams_are_merged_by_time() {
+        let (text_tx, text_rx) = mpsc::sync_channel(16);
+        // each chunk starts with a bare newline, the streams take turns by chunk
+        for segment in [
+            segment(0, 0, "\n"), segment(0, 0, " Hello."), segment(0, 2000, " How can I help?"),
+            segment(1, 0, "\n"), segment(1, 1000, " Hi."), segment(1, 3000, " My order is late."),
+        ] {
+            text_tx.send(segment).unwrap();
+        }
+        drop(text_tx);
+        let (progress_tx, progress_rx) = mpsc::channel();
+        let speakers = vec!["agent".to_string(), "customer".to_string()];
+        let transcript = collect_segments(text_rx, &speakers, &ProgressReporter::from_sender(progress_tx), false);
+        assert_eq!(transcript.text, vec![" Hello.", " Hi.", " How can I help?", " My order is late."]);
+        assert_eq!(transcript.labeled_lines(), vec![
+            "[00:00:00.000 --> 00:00:00.900] agent: Hello.",
+            "[00:00:01.000 --> 00:00:01.900] customer: Hi.",
+            "[00:00:02.000 --> 00:00:02.900] agent: How can I help?",
+            "[00:00:03.000 --> 00:00:03.900] customer: My order is late.",
+        ]);
+        // reported as they come
+        let reported: Vec<String> = progress_rx.iter().map(|event| match event {
+            ProgressEvent::Segment(segment) => segment.label(),
+            event => panic!("{:?}", event),
+        }).collect();
+        assert_eq!(reported, vec!["agent", "agent", "customer", "customer"]);
+    }
+
+    #[test]
+    fn live_segments_are_only_reported() {
+        let (text_tx, text_rx) = mpsc::sync_channel(1);
+        text_tx.send(segment(1, 0, " Hi.")).unwrap();
+        drop(text_tx);
+        let (progress_tx, progress_rx) = mpsc::channel();
+        let transcript = collect_segments(text_rx, &[], &ProgressReporter::from_sender(progress_tx), true);
+        assert!(transcript.segments.is_empty() && transcript.text.is_empty());
+        // no speaker names given
+        assert!(matches!(progress_rx.recv().unwrap(), ProgressEvent::Segment(segment) if segment.label() == "channel 1"));
+    }
+}
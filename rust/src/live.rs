@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+/// Finds the holes in the timeline of a live input, so they can be filled with silence
+/// and the segment times keep matching the stream.
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+pub(crate) struct GapTracker {
+    threshold: Duration,
+    max_gap: Duration,
+    // where the next frame should start if the stream is continuous
+    expected: Option<Duration>,
+}
+
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+impl GapTracker {
+    pub(crate) fn new(threshold: Duration, max_gap: Duration) -> Self {
+        Self { threshold, max_gap, expected: None }
+    }
+
+    /// Takes the timestamp and length of a decoded frame, returns the silence to insert before it.
+    ///
+    /// A timestamp going backwards starts a new timeline, as streams do after a restart
+    /// or an HLS discontinuity, and isn't a gap.
+    pub(crate) fn frame(&mut self, pts: Option<Duration>, len: Duration) -> Duration {
+        let gap = match (pts, self.expected) {
+            (Some(pts), Some(expected)) if pts > expected + self.threshold => (pts - expected).min(self.max_gap),
+            _ => Duration::ZERO,
+        };
+        self.expected = match pts {
+            Some(pts) => Some(pts + len),
+            None => self.expected.map(|expected| expected + len),
+        };
+        gap
+    }
+
+    /// Takes the time the input was down before a reconnect, returns the silence to insert.
+    /// The reconnected stream starts a new timeline.
+    pub(crate) fn reconnected(&mut self, outage: Duration) -> Duration {
+        self.expected = None;
+        if outage > self.threshold {
+            outage.min(self.max_gap)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn tracker() -> GapTracker {
+        GapTracker::new(ms(500), ms(10_000))
+    }
+
+    #[test]
+    fn continuous_stream_has_no_gaps() {
+        let mut gaps = tracker();
+        for i in 0..100 {
+            assert_eq!(gaps.frame(Some(ms(i * 20)), ms(20)), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn jitter_below_threshold_is_ignored() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(0)), ms(20));
+        assert_eq!(gaps.frame(Some(ms(400)), ms(20)), Duration::ZERO);
+    }
+
+    #[test]
+    fn timestamp_jump_is_a_gap() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(0)), ms(20));
+        gaps.frame(Some(ms(20)), ms(20));
+        assert_eq!(gaps.frame(Some(ms(3040)), ms(20)), ms(3000));
+        // continuous again after the gap
+        assert_eq!(gaps.frame(Some(ms(3060)), ms(20)), Duration::ZERO);
+    }
+
+    #[test]
+    fn gaps_are_capped() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(0)), ms(20));
+        assert_eq!(gaps.frame(Some(ms(3_600_000)), ms(20)), ms(10_000));
+    }
+
+    #[test]
+    fn backwards_timestamp_starts_a_new_timeline() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(60_000)), ms(20));
+        assert_eq!(gaps.frame(Some(ms(0)), ms(20)), Duration::ZERO);
+        assert_eq!(gaps.frame(Some(ms(20)), ms(20)), Duration::ZERO);
+    }
+
+    #[test]
+    fn frames_without_timestamp_extend_the_timeline() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(0)), ms(20));
+        gaps.frame(None, ms(20));
+        gaps.frame(None, ms(20));
+        assert_eq!(gaps.frame(Some(ms(60)), ms(20)), Duration::ZERO);
+    }
+
+    #[test]
+    fn reconnect_fills_the_outage() {
+        let mut gaps = tracker();
+        gaps.frame(Some(ms(5000)), ms(20));
+        assert_eq!(gaps.reconnected(ms(2000)), ms(2000));
+        assert_eq!(gaps.reconnected(ms(100)), Duration::ZERO);
+        assert_eq!(gaps.reconnected(ms(60_000)), ms(10_000));
+        // the new connection starts over at 0
+        assert_eq!(gaps.frame(Some(ms(0)), ms(20)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::Once;
+use log::Level;
+use crate::errors::WhisperError;
+use crate::ffi;
+
+/// `log` target of every record coming from whisper.cpp, ggml and the C++ wrapper.
+pub const NATIVE_LOG_TARGET: &str = "whisper_cpp";
+
+// ggml_log_level values, see ggml.h
+const GGML_LOG_LEVEL_ERROR: i32 = 2;
+const GGML_LOG_LEVEL_WARN: i32 = 3;
+const GGML_LOG_LEVEL_INFO: i32 = 4;
+
+thread_local! {
+    // whisper.cpp sometimes logs a line in several pieces, keep the unfinished part here
+    static PENDING_LINE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn map_level(level: i32) -> Level {
+    match level {
+        GGML_LOG_LEVEL_ERROR => Level::Error,
+        GGML_LOG_LEVEL_WARN => Level::Warn,
+        GGML_LOG_LEVEL_INFO => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+/// Called from C++ for every native log message.
+pub fn whisper_log(level: i32, text: &[u8]) {
+    let level = map_level(level);
+    let text = String::from_utf8_lossy(text);
+    PENDING_LINE.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.push_str(&text);
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            let line = line.trim_end();
+            if !line.is_empty() {
+                log::log!(target: NATIVE_LOG_TARGET, level, "{}", line);
+            }
+        }
+    });
+}
+
+/// Installs the whisper.cpp log callback that forwards into the `log` crate.
+/// Only the first call has an effect.
+pub fn install_native_logging() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe { ffi::install_whisper_log_callback() });
+}
+
+/// Output format of the logger installed by `init_logging`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `[rust_wrapper][timestamp]<level> - message`
+    #[default]
+    RustWrapper,
+    /// The stock `env_logger` format.
+    EnvLogger,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `env_logger` filter directives, e.g. `info,whisper_cpp=warn`.
+    /// Falls back to `RUST_LOG` when `None`.
+    pub filter: Option<String>,
+}
+
+/// Installs an `env_logger` as the global logger.
+///
+/// This is opt-in: host applications that configure their own logger should not call it.
+/// Returns an error if a global logger is already set.
+pub fn init_logging(config: LoggingConfig) -> Result<(), WhisperError> {
+    let mut builder = match &config.filter {
+        Some(filter) => {
+            let mut builder = env_logger::Builder::new();
+            builder.parse_filters(filter);
+            builder
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    if config.format == LogFormat::RustWrapper {
+        let logger_name = "rust_wrapper";
+        builder.format(move |buf, record| {
+            writeln!(buf, "[{}][{}]<{}> - {}",
+                     &logger_name,
+                     chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                     record.level(), record.args())
+        });
+    }
+    builder.try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, Once};
+    use std::thread::{self, ThreadId};
+    use log::{Level, Log, Metadata, Record};
+    use crate::logging::{whisper_log, GGML_LOG_LEVEL_ERROR, GGML_LOG_LEVEL_INFO, GGML_LOG_LEVEL_WARN, NATIVE_LOG_TARGET};
+
+    // records of every test thread, each test only looks at its own
+    static RECORDS: Mutex<Vec<(ThreadId, Level, String)>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target() == NATIVE_LOG_TARGET {
+                RECORDS.lock().unwrap().push((thread::current().id(), record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn logged() -> Vec<(Level, String)> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_boxed_logger(Box::new(Capture)).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        let id = thread::current().id();
+        RECORDS.lock().unwrap().iter()
+            .filter(|(thread, _, _)| *thread == id)
+            .map(|(_, level, text)| (*level, text.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn partial_lines_are_joined() {
+        logged();
+        whisper_log(GGML_LOG_LEVEL_INFO, b"whisper_init: loading ");
+        assert!(logged().is_empty());
+        whisper_log(GGML_LOG_LEVEL_INFO, b"model ... done\nsecond");
+        whisper_log(GGML_LOG_LEVEL_INFO, b" line\n\n");
+        assert_eq!(logged(), vec![
+            (Level::Info, "whisper_init: loading model ... done".to_string()),
+            (Level::Info, "second line".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn levels_are_mapped() {
+        logged();
+        whisper_log(GGML_LOG_LEVEL_ERROR, b"error\n");
+        whisper_log(GGML_LOG_LEVEL_WARN, b"warn\n");
+        whisper_log(GGML_LOG_LEVEL_INFO, b"info\n");
+        // GGML_LOG_LEVEL_DEBUG and anything unknown
+        whisper_log(5, b"debug\n");
+        whisper_log(42, b"other\n");
+        assert_eq!(logged(), vec![
+            (Level::Error, "error".to_string()),
+            (Level::Warn, "warn".to_string()),
+            (Level::Info, "info".to_string()),
+            (Level::Debug, "debug".to_string()),
+            (Level::Debug, "other".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn truncated_wrapper_message() {
+        logged();
+        // wrapper_log keeps the first 1022 bytes of a longer message and ends it with a
+        // newline, which may cut a UTF-8 sequence
+        let mut message = vec![b'a'; 1021];
+        message.push("é".as_bytes()[0]);
+        message.push(b'\n');
+        assert_eq!(message.len(), 1023);
+        whisper_log(GGML_LOG_LEVEL_WARN, &message);
+        whisper_log(GGML_LOG_LEVEL_WARN, b"next\n");
+        let logged = logged();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[0].1, format!("{}\u{FFFD}", "a".repeat(1021)));
+        assert_eq!(logged[1], (Level::Warn, "next".to_string()));
+    }
+}
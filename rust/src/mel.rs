@@ -0,0 +1,426 @@
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::clock::SampleClock;
+use crate::errors::WhisperError;
+
+/// Samples of a STFT frame, 25 ms at 16 kHz.
+pub const N_FFT: usize = 400;
+/// Samples between two frames, 10 ms, the unit of whisper's timestamps.
+pub const HOP_LENGTH: usize = 160;
+// power bins of a frame, from 0 Hz to Nyquist
+const N_BINS: usize = N_FFT / 2 + 1;
+// whisper pads the audio it transcribes with 30 s of silence
+const PAD_FRAMES: usize = 3000;
+// log10 of the power floor, the value of silent frames
+const LOG_FLOOR: f32 = -10.0;
+
+// the Slaney mel scale of librosa, linear below 1 kHz and logarithmic above
+const F_SP: f64 = 200.0 / 3.0;
+const MIN_LOG_HZ: f64 = 1000.0;
+const MIN_LOG_MEL: f64 = MIN_LOG_HZ / F_SP;
+
+fn log_step() -> f64 {
+    6.4f64.ln() / 27.0
+}
+
+fn hz_to_mel(hz: f64) -> f64 {
+    if hz >= MIN_LOG_HZ {
+        MIN_LOG_MEL + (hz / MIN_LOG_HZ).ln() / log_step()
+    } else {
+        hz / F_SP
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    if mel >= MIN_LOG_MEL {
+        MIN_LOG_HZ * (log_step() * (mel - MIN_LOG_MEL)).exp()
+    } else {
+        mel * F_SP
+    }
+}
+
+/// The mel filterbank whisper's models ship as `mel_filters`, `librosa.filters.mel(sr=16000,
+/// n_fft=400, n_mels=n_mel)`: `n_mel` rows of triangular, area normalized filters over the
+/// power bins of a frame.
+pub fn mel_filters(n_mel: usize) -> Vec<f32> {
+    let nyquist = SampleClock::WHISPER.rate() as f64 / 2.0;
+    let max_mel = hz_to_mel(nyquist);
+    // band edges, evenly spaced on the mel scale
+    let edges: Vec<f64> = (0..n_mel + 2).map(|i| mel_to_hz(max_mel * i as f64 / (n_mel + 1) as f64)).collect();
+    let mut filters = vec![0.0f32; n_mel * N_BINS];
+    for (band, weights) in filters.chunks_exact_mut(N_BINS).enumerate() {
+        let (low, center, high) = (edges[band], edges[band + 1], edges[band + 2]);
+        let norm = 2.0 / (high - low);
+        for (bin, weight) in weights.iter_mut().enumerate() {
+            let freq = bin as f64 * nyquist / (N_BINS - 1) as f64;
+            let rising = (freq - low) / (center - low);
+            let falling = (high - freq) / (high - center);
+            *weight = (rising.min(falling).max(0.0) * norm) as f32;
+        }
+    }
+    filters
+}
+
+/// Log mel power of single frames.
+struct Stft {
+    n_mel: usize,
+    filters: Vec<f32>,
+    window: Vec<f32>,
+    fft: Arc<dyn Fft<f32>>,
+    buf: Vec<Complex<f32>>,
+}
+
+impl Stft {
+    fn new(n_mel: usize) -> Self {
+        // periodic Hann window, as torch.hann_window
+        let window = (0..N_FFT)
+            .map(|i| 0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / N_FFT as f32).cos()))
+            .collect();
+        Self {
+            n_mel,
+            filters: mel_filters(n_mel),
+            window,
+            fft: FftPlanner::new().plan_fft_forward(N_FFT),
+            buf: vec![Complex::default(); N_FFT],
+        }
+    }
+
+    /// Appends the log10 mel power of the frame starting with `samples` to `out`, the
+    /// samples past the end of `samples` are taken as zeros.
+    fn frame(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        for (i, x) in self.buf.iter_mut().enumerate() {
+            *x = Complex::new(samples.get(i).map_or(0.0, |s| s * self.window[i]), 0.0);
+        }
+        self.fft.process(&mut self.buf);
+        for weights in self.filters.chunks_exact(N_BINS) {
+            let power: f64 = weights.iter().zip(&self.buf).map(|(w, x)| (w * x.norm_sqr()) as f64).sum();
+            out.push(power.max(1e-10).log10() as f32);
+        }
+    }
+}
+
+/// A chunk's log mel spectrogram as whisper takes it: `n_mel` rows of `n_len` frames,
+/// clamped and normalized like `whisper_pcm_to_mel`.
+pub struct MelChunk {
+    pub data: Vec<f32>,
+    pub n_mel: usize,
+    pub n_len: usize,
+    /// Frames of audio, the ones after them are the padding.
+    pub n_audio: usize,
+}
+
+/// Whisper's log mel spectrogram of a stream, computed as its samples come in.
+///
+/// Frame `i` is centered on sample `i * HOP_LENGTH` of the stream, which is reflected at
+/// its start and padded with zeros at its end like `whisper_pcm_to_mel` does. The frames
+/// are kept until discarded, so chunks overlapping earlier ones reuse them.
+pub struct MelSpectrogram {
+    stft: Stft,
+    // position of the first sample of the stream
+    origin: usize,
+    // samples pushed since `origin`
+    pushed: usize,
+    // the padded stream from padded position `padded_start` on, the padded position of a
+    // sample is its index in the stream plus N_FFT / 2
+    padded: Vec<f32>,
+    padded_start: usize,
+    // whether the start of the stream is reflected, it takes its first N_FFT / 2 + 1 samples
+    reflected: bool,
+    // log10 mel power of the frames from `first_frame` on, the bands of a frame together
+    frames: Vec<f32>,
+    first_frame: usize,
+    // frames of the stream, once it ended
+    end_frame: Option<usize>,
+}
+
+impl MelSpectrogram {
+    /// `n_mel` is the model's, 80 or 128 bands.
+    pub fn new(n_mel: usize) -> Self {
+        Self {
+            stft: Stft::new(n_mel),
+            origin: 0,
+            pushed: 0,
+            padded: Vec::new(),
+            padded_start: 0,
+            reflected: false,
+            frames: Vec::new(),
+            first_frame: 0,
+            end_frame: None,
+        }
+    }
+
+    pub fn n_mel(&self) -> usize {
+        self.stft.n_mel
+    }
+
+    /// Position of the next sample to push.
+    pub fn position(&self) -> usize {
+        self.origin + self.pushed
+    }
+
+    /// Starts a new stream at `pos`, after a gap in the samples.
+    pub fn restart(&mut self, pos: usize) {
+        self.origin = pos;
+        self.pushed = 0;
+        self.padded.clear();
+        self.padded_start = 0;
+        self.reflected = false;
+        self.frames.clear();
+        self.first_frame = 0;
+        self.end_frame = None;
+    }
+
+    /// Appends the next samples of the stream and computes the frames they complete.
+    pub fn push(&mut self, samples: &[f32]) {
+        debug_assert!(self.end_frame.is_none(), "samples pushed after the end of the stream");
+        self.padded.extend_from_slice(samples);
+        self.pushed += samples.len();
+        if !self.reflected && self.pushed > N_FFT / 2 {
+            self.reflect();
+        }
+        self.compute();
+    }
+
+    /// Ends the stream, computing its last frames.
+    pub fn finish(&mut self) {
+        if !self.reflected {
+            self.reflect();
+        }
+        // the last frame starts at the end of the samples, the ones after it are silence
+        self.end_frame = Some((self.pushed + N_FFT / 2) / HOP_LENGTH + 1);
+        self.padded.resize(self.padded.len() + N_FFT, 0.0);
+        self.compute();
+    }
+
+    /// Drops the frames before the sample at `pos`, no chunk starts before it anymore.
+    pub fn discard(&mut self, pos: usize) {
+        let frame = pos.saturating_sub(self.origin) / HOP_LENGTH;
+        let drop = frame.saturating_sub(self.first_frame).min(self.computed() - self.first_frame);
+        self.frames.drain(..drop * self.stft.n_mel);
+        self.first_frame += drop;
+    }
+
+    /// Spectrogram of the `len` samples from `pos` on, for `whisper_set_mel`. `pos` is a
+    /// multiple of `HOP_LENGTH` samples into the stream, and not discarded.
+    ///
+    /// Like `whisper_pcm_to_mel` on these samples, except for the frames at the start
+    /// which see the samples before them instead of a reflection. Samples not pushed yet
+    /// are silence.
+    pub fn chunk(&mut self, pos: usize, len: usize) -> Result<MelChunk, WhisperError> {
+        if pos < self.origin {
+            return Err(WhisperError::DecoderInput(format!(
+                "chunk at sample {} is before the stream, which starts at {}", pos, self.origin)));
+        }
+        let first = (pos - self.origin) / HOP_LENGTH;
+        if first < self.first_frame {
+            return Err(WhisperError::DecoderInput(format!(
+                "chunk at sample {} starts in discarded frames", pos)));
+        }
+        if !self.reflected {
+            // a stream this short only has zeros to reflect
+            self.reflect();
+        }
+        let n_mel = self.stft.n_mel;
+        let n_len = len / HOP_LENGTH + PAD_FRAMES;
+        // frames starting before the end of the chunk, the others are silence
+        let n_frames = ((len + N_FFT / 2) / HOP_LENGTH + 1).min(n_len);
+        let mut frames = Vec::with_capacity(n_frames * n_mel);
+        let cached = self.computed().saturating_sub(first).min(n_frames);
+        if cached > 0 {
+            let from = (first - self.first_frame) * n_mel;
+            frames.extend_from_slice(&self.frames[from..from + cached * n_mel]);
+        }
+        // frames waiting for samples after the chunk, computed as whisper does without them
+        let chunk_end = pos - self.origin + len + N_FFT / 2;
+        for frame in first + cached..first + n_frames {
+            let start = frame * HOP_LENGTH - self.padded_start;
+            let end = (frame * HOP_LENGTH + N_FFT).min(chunk_end) - self.padded_start;
+            let samples = self.padded.get(start..end.min(self.padded.len())).unwrap_or(&[]);
+            self.stft.frame(samples, &mut frames);
+        }
+        frames.resize(n_len * n_mel, LOG_FLOOR);
+
+        // clamped to 80 dB below the loudest, then scaled to about [-1, 1]
+        let max = frames.iter().copied().fold(f32::MIN, f32::max) - 8.0;
+        let mut data = vec![0.0f32; n_len * n_mel];
+        for (i, frame) in frames.chunks_exact(n_mel).enumerate() {
+            for (band, value) in frame.iter().enumerate() {
+                data[band * n_len + i] = (value.max(max) + 4.0) / 4.0;
+            }
+        }
+        Ok(MelChunk { data, n_mel, n_len, n_audio: 1 + len.saturating_sub(N_FFT / 2) / HOP_LENGTH })
+    }
+
+    // index after the last computed frame
+    fn computed(&self) -> usize {
+        self.first_frame + self.frames.len() / self.stft.n_mel
+    }
+
+    // mirrors samples 1..=N_FFT / 2 in front of the stream, missing ones are zeros
+    fn reflect(&mut self) {
+        let head: Vec<f32> = (1..=N_FFT / 2).rev().map(|i| self.padded.get(i).copied().unwrap_or(0.0)).collect();
+        self.padded.splice(0..0, head);
+        self.reflected = true;
+    }
+
+    fn compute(&mut self) {
+        if !self.reflected {
+            return;
+        }
+        let available = self.padded_start + self.padded.len();
+        let mut frame = self.computed();
+        while frame * HOP_LENGTH + N_FFT <= available && self.end_frame.is_none_or(|end| frame < end) {
+            let start = frame * HOP_LENGTH - self.padded_start;
+            self.stft.frame(&self.padded[start..start + N_FFT], &mut self.frames);
+            frame += 1;
+        }
+        // the samples before the next frame are no longer needed
+        let used = (frame * HOP_LENGTH - self.padded_start).min(self.padded.len());
+        self.padded.drain(..used);
+        self.padded_start += used;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * std::f32::consts::PI * freq * i as f32 / 16000.0).sin() * 0.5).collect()
+    }
+
+    #[test]
+    fn filters_are_triangles() {
+        for n_mel in [80, 128] {
+            let filters = mel_filters(n_mel);
+            for weights in filters.chunks_exact(N_BINS) {
+                let peak = weights.iter().copied().enumerate().fold((0, 0.0), |a, b| if b.1 > a.1 { b } else { a }).0;
+                assert!(weights[..peak].windows(2).all(|w| w[0] <= w[1]));
+                assert!(weights[peak..].windows(2).all(|w| w[0] >= w[1]));
+            }
+        }
+        // the first band of whisper's mel_filters
+        let filters = mel_filters(80);
+        assert!((filters[1] - 0.024_862_4).abs() < 1e-6, "{}", filters[1]);
+        assert_eq!(filters[2], 0.0);
+    }
+
+    #[test]
+    fn pushed_in_pieces() {
+        let samples = sine(440.0, 16000 * 2 + 77);
+        let mut whole = MelSpectrogram::new(80);
+        whole.push(&samples);
+        whole.finish();
+        let mut pieces = MelSpectrogram::new(80);
+        for piece in samples.chunks(97) {
+            pieces.push(piece);
+        }
+        pieces.finish();
+        assert_eq!(whole.frames, pieces.frames);
+        assert_eq!(whole.computed(), (samples.len() + N_FFT / 2) / HOP_LENGTH + 1);
+        assert_eq!(whole.chunk(0, samples.len()).unwrap().data, pieces.chunk(0, samples.len()).unwrap().data);
+    }
+
+    #[test]
+    fn chunk_before_the_next_samples() {
+        // the last frames of the first chunk are computed without the second one
+        let samples = sine(1000.0, 16000 * 2);
+        let mut streamed = MelSpectrogram::new(80);
+        streamed.push(&samples[..16000]);
+        let early = streamed.chunk(0, 16000).unwrap();
+        streamed.push(&samples[16000..]);
+        let late = streamed.chunk(0, 16000).unwrap();
+        assert_eq!((early.n_len, early.n_audio), (3100, 99));
+        // the frames before the end are the same, the ones reaching past it see zeros in one of them
+        let band = 26 * early.n_len;
+        assert_eq!(early.data[band..band + 98], late.data[band..band + 98]);
+        assert_ne!(early.data[band + 99], late.data[band + 99]);
+        // the second chunk goes on from the cached frames
+        streamed.discard(16000);
+        let second = streamed.chunk(16000, 16000).unwrap();
+        assert_eq!(second.n_len, 3100);
+    }
+
+    #[test]
+    fn sine_lands_in_its_band() {
+        let mut mel = MelSpectrogram::new(80);
+        mel.push(&sine(1000.0, 16000));
+        let chunk = mel.chunk(0, 16000).unwrap();
+        let frame = 50;
+        let loudest = (0..chunk.n_mel).max_by(|a, b| {
+            chunk.data[a * chunk.n_len + frame].total_cmp(&chunk.data[b * chunk.n_len + frame])
+        });
+        // 1 kHz is 15 mel, band 26 is centered on 27 * 45.25 / 81
+        assert_eq!(loudest, Some(26));
+        // the padding is clamped to 80 dB below the peak
+        let peak = chunk.data.iter().copied().fold(f32::MIN, f32::max);
+        assert_eq!(chunk.data[chunk.n_len - 1], peak - 2.0);
+    }
+
+    #[test]
+    fn silence() {
+        let mut mel = MelSpectrogram::new(128);
+        mel.push(&[0.0; 4800]);
+        mel.finish();
+        let chunk = mel.chunk(0, 4800).unwrap();
+        assert_eq!(chunk.data.len(), 128 * 3030);
+        assert!(chunk.data.iter().all(|x| *x == (LOG_FLOOR + 4.0) / 4.0));
+    }
+
+    #[test]
+    fn chunk_ahead_of_the_samples() {
+        let mut mel = MelSpectrogram::new(80);
+        mel.push(&sine(440.0, 1000));
+        // past the pushed samples, the frames are silence
+        let chunk = mel.chunk(3200, 1600).unwrap();
+        assert_eq!((chunk.n_len, chunk.n_audio), (3010, 9));
+        assert!(chunk.data.iter().all(|x| *x == (LOG_FLOOR + 4.0) / 4.0));
+        // the frames not computed yet see the pushed samples
+        let chunk = mel.chunk(800, 1600).unwrap();
+        assert!(chunk.data[26 * chunk.n_len..].iter().any(|x| *x != chunk.data[chunk.n_len - 1]));
+    }
+
+    #[test]
+    fn chunk_before_the_kept_frames() {
+        let mut mel = MelSpectrogram::new(80);
+        mel.restart(1600);
+        mel.push(&sine(440.0, 3200));
+        assert!(matches!(mel.chunk(0, 1600), Err(WhisperError::DecoderInput(_))));
+        mel.discard(3200);
+        assert!(matches!(mel.chunk(1600, 1600), Err(WhisperError::DecoderInput(_))));
+        assert!(mel.chunk(3200, 1600).is_ok());
+    }
+
+    #[test]
+    fn matches_whisper() {
+        // 440 Hz and 3.1 kHz for 250 ms, the expected values are from whisper's log_mel_spectrogram
+        let samples: Vec<f32> = (0..4000).map(|i| {
+            let t = 2.0 * std::f64::consts::PI * i as f64 / 16000.0;
+            ((440.0 * t).sin() * 0.5 + (3100.0 * t).sin() * 0.15) as f32
+        }).collect();
+        const BANDS: [usize; 8] = [0, 7, 10, 20, 37, 50, 64, 79];
+        const FRAMES: [(usize, [f32; 8]); 7] = [
+            (0, [0.991097, 1.127318, 1.337681, 0.798799, 0.282884, 0.456274, 0.386470, 0.185248]),
+            (1, [0.479137, 0.514884, 1.35025, 0.315256, -0.210228, -0.049527, -0.123671, -0.324852]),
+            (12, [-0.561796, -0.561796, 1.348738, -0.561796, -0.561796, -0.561796, -0.561796, -0.561796]),
+            (24, [0.328622, 0.364367, 1.349485, 0.164739, -0.360410, -0.194272, -0.274735, -0.475364]),
+            (25, [0.840582, 0.976803, 1.268093, 0.648284, 0.132369, 0.305761, 0.235956, 0.034733]),
+            (26, [0.328622, 0.364370, 0.359197, 0.164746, -0.361083, -0.205855, -0.273639, -0.475371]),
+            (3000, [-0.561796; 8]),
+        ];
+        let mut mel = MelSpectrogram::new(80);
+        mel.push(&samples);
+        mel.finish();
+        let chunk = mel.chunk(0, samples.len()).unwrap();
+        assert_eq!((chunk.n_len, chunk.n_audio), (3025, 24));
+        for (frame, expected) in FRAMES {
+            for (band, expected) in BANDS.iter().zip(expected) {
+                let actual = chunk.data[band * chunk.n_len + frame];
+                assert!((actual - expected).abs() < 1e-4, "frame {} band {}: {} != {}", frame, band, actual, expected);
+            }
+        }
+    }
+}
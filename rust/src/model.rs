@@ -0,0 +1,216 @@
+use cxx::UniquePtr;
+
+use crate::errors::WhisperError;
+use crate::ffi;
+use crate::mel::MelChunk;
+
+/// A token id of the model's vocabulary.
+pub type Token = i32;
+
+/// Tokens with a fixed meaning in whisper's decoder prompt and output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpecialToken {
+    EndOfText,
+    StartOfTranscript,
+    StartOfLm,
+    /// Precedes the text of the previous window.
+    Previous,
+    NoSpeech,
+    NoTimestamps,
+    /// `<|0.00|>`, the following ids are timestamps in steps of 20 ms.
+    TimestampBegin,
+    Translate,
+    Transcribe,
+}
+
+impl From<SpecialToken> for ffi::SpecialToken {
+    fn from(token: SpecialToken) -> Self {
+        match token {
+            SpecialToken::EndOfText => ffi::SpecialToken::EndOfText,
+            SpecialToken::StartOfTranscript => ffi::SpecialToken::StartOfTranscript,
+            SpecialToken::StartOfLm => ffi::SpecialToken::StartOfLm,
+            SpecialToken::Previous => ffi::SpecialToken::Previous,
+            SpecialToken::NoSpeech => ffi::SpecialToken::NoSpeech,
+            SpecialToken::NoTimestamps => ffi::SpecialToken::NoTimestamps,
+            SpecialToken::TimestampBegin => ffi::SpecialToken::TimestampBegin,
+            SpecialToken::Translate => ffi::SpecialToken::Translate,
+            SpecialToken::Transcribe => ffi::SpecialToken::Transcribe,
+        }
+    }
+}
+
+/// A whisper model with its own decoding state, for decoding loops written on top of the
+/// encoder and decoder rather than `whisper_full`.
+///
+/// A mel chunk is encoded once, then token sequences are run through the decoder against it.
+/// The decoder keeps the keys and values of the tokens it has seen, so a sequence can extend
+/// the first `n_past` tokens of the ones decoded before instead of decoding them again.
+pub struct Model {
+    inner: UniquePtr<ffi::WhisperWrapper>,
+    n_threads: i32,
+    encoded: bool,
+    // tokens in the decoder's cache since the last encode
+    decoded: usize,
+}
+
+// the wrapper owns its context and state, nothing is shared with other threads
+unsafe impl Send for Model {}
+
+impl Model {
+    pub fn load(model_path: &str) -> Result<Self, WhisperError> {
+        let inner = unsafe { ffi::create_whisper_wrapper(model_path) }
+            .map_err(|e| WhisperError::ModelLoad(e.to_string()))?;
+        Ok(Model { inner, n_threads: 4, encoded: false, decoded: 0 })
+    }
+
+    /// Sets the threads the encoder and decoder run on.
+    pub fn with_threads(mut self, n_threads: usize) -> Self {
+        self.n_threads = n_threads.max(1) as i32;
+        self
+    }
+
+    pub fn n_mels(&self) -> usize {
+        unsafe { self.inner.n_mels() as usize }
+    }
+
+    pub fn n_vocab(&self) -> usize {
+        unsafe { self.inner.n_vocab() as usize }
+    }
+
+    /// Tokens the decoder can hold, prompt included.
+    pub fn n_text_ctx(&self) -> usize {
+        unsafe { self.inner.n_text_ctx() as usize }
+    }
+
+    pub fn is_multilingual(&self) -> bool {
+        unsafe { self.inner.is_multilingual() }
+    }
+
+    /// Runs the encoder on the 30 s window of `mel` starting at frame `offset`, frames past
+    /// the end of the chunk are padding. Clears the decoder's cache.
+    pub fn encode(&mut self, mel: &MelChunk, offset: usize) -> Result<(), WhisperError> {
+        if mel.n_mel != self.n_mels() || mel.data.len() != mel.n_mel * mel.n_len {
+            return Err(WhisperError::DecoderInput(format!(
+                "mel chunk of {} bands, the model takes {}", mel.n_mel, self.n_mels())));
+        }
+        if offset >= mel.n_len {
+            return Err(WhisperError::DecoderInput(format!(
+                "offset {} is past the {} frames of the chunk", offset, mel.n_len)));
+        }
+        self.encoded = false;
+        self.decoded = 0;
+        let ret = unsafe { self.inner.encode(mel.data.as_ptr(), mel.n_len, offset as i32, self.n_threads) };
+        if ret != 0 {
+            return Err(WhisperError::Encoder(ret));
+        }
+        self.encoded = true;
+        Ok(())
+    }
+
+    /// Decodes `tokens` after the first `n_past` tokens of the decoder's cache and returns the
+    /// logits of the last one, over the whole vocabulary.
+    pub fn decode(&mut self, tokens: &[Token], n_past: usize) -> Result<&[f32], WhisperError> {
+        if !self.encoded {
+            return Err(WhisperError::DecoderInput("nothing has been encoded".to_string()));
+        }
+        if tokens.is_empty() {
+            return Err(WhisperError::DecoderInput("no tokens to decode".to_string()));
+        }
+        if let Some(token) = tokens.iter().find(|t| **t < 0 || **t as usize >= self.n_vocab()) {
+            return Err(WhisperError::DecoderInput(format!("token {} is not in the vocabulary", token)));
+        }
+        if n_past > self.decoded {
+            return Err(WhisperError::DecoderInput(format!(
+                "n_past is {}, only {} tokens were decoded", n_past, self.decoded)));
+        }
+        if n_past + tokens.len() > self.n_text_ctx() {
+            return Err(WhisperError::DecoderInput(format!(
+                "{} tokens don't fit the context of {}", n_past + tokens.len(), self.n_text_ctx())));
+        }
+        // what followed n_past is overwritten, or stale if the decoder fails
+        self.decoded = n_past;
+        let ret = unsafe { self.inner.decode(tokens, n_past as i32, self.n_threads) };
+        if ret != 0 {
+            return Err(WhisperError::Decoder(ret));
+        }
+        self.decoded = n_past + tokens.len();
+        Ok(self.logits())
+    }
+
+    /// Logits of the last token decoded, empty if the last `decode` failed or `encode` came after it.
+    pub fn logits(&self) -> &[f32] {
+        unsafe { self.inner.logits() }
+    }
+
+    pub fn tokenize(&self, text: &str) -> Vec<Token> {
+        unsafe { self.inner.tokenize(text) }
+    }
+
+    /// The bytes of `token`, which may be part of a UTF-8 sequence split across tokens.
+    pub fn token_bytes(&self, token: Token) -> Option<&[u8]> {
+        if token < 0 || token as usize >= self.n_vocab() {
+            return None;
+        }
+        Some(unsafe { self.inner.token_bytes(token) })
+    }
+
+    pub fn special_token(&self, token: SpecialToken) -> Token {
+        unsafe { self.inner.special_token(token.into()) }
+    }
+
+    /// The token of a whisper language code, none if the model is English only.
+    pub fn language_token(&self, lang: &str) -> Option<Token> {
+        if !self.is_multilingual() {
+            return None;
+        }
+        match unsafe { self.inner.lang_token(lang) } {
+            -1 => None,
+            token => Some(token),
+        }
+    }
+}
+
+// needs whisper and a model: `WHISPER_MODEL=<path> cargo test --features link-whisper`
+#[cfg(all(test, feature = "link-whisper"))]
+mod tests {
+    use crate::mel::MelSpectrogram;
+    use crate::model::{Model, SpecialToken};
+
+    fn model() -> Model {
+        let path = std::env::var("WHISPER_MODEL").unwrap_or(crate::config::DEFAULT_MODEL_PATH.to_string());
+        Model::load(&path).unwrap()
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let model = model();
+        let tokens = model.tokenize(" Hello world, ça va?");
+        assert!(!tokens.is_empty());
+        let bytes: Vec<u8> = tokens.iter().flat_map(|t| model.token_bytes(*t).unwrap().to_vec()).collect();
+        assert_eq!(String::from_utf8(bytes).unwrap(), " Hello world, ça va?");
+        assert_eq!(model.token_bytes(model.n_vocab() as i32), None);
+    }
+
+    #[test]
+    fn decode_extends_the_cache() {
+        let mut model = model();
+        let mut mel = MelSpectrogram::new(model.n_mels());
+        mel.push(&[0.0; 16000]);
+        mel.finish();
+        let chunk = mel.chunk(0, 16000).unwrap();
+        assert!(model.decode(&[model.special_token(SpecialToken::StartOfTranscript)], 0).is_err());
+        model.encode(&chunk, 0).unwrap();
+
+        let mut prompt = vec![model.special_token(SpecialToken::StartOfTranscript)];
+        prompt.extend(model.language_token("en"));
+        prompt.push(model.special_token(SpecialToken::Transcribe));
+        prompt.push(model.special_token(SpecialToken::NoTimestamps));
+        let n_vocab = model.n_vocab();
+        let logits = model.decode(&prompt, 0).unwrap();
+        assert_eq!(logits.len(), n_vocab);
+        assert!(logits.iter().all(|x| x.is_finite()));
+        let eot = model.special_token(SpecialToken::EndOfText);
+        assert_eq!(model.decode(&[eot], prompt.len()).unwrap().len(), n_vocab);
+        assert!(model.decode(&[eot], prompt.len() + 2).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{ErrorKind, Read};
+use std::time::Duration;
+
+use log::debug;
+use crate::channels::{ChannelSplitter, StreamOpener};
+use crate::config::SessionConfig;
+use crate::errors::WhisperError;
+use crate::progress::{ProgressEvent, DECODE_PROGRESS_STEP};
+use crate::rb::OverflowPolicy;
+
+// frames read from the source per iteration
+const READ_FRAMES: usize = 4096;
+
+/// Sample encoding of headerless PCM audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmFormat {
+    /// Unsigned 8 bit.
+    U8,
+    /// Signed 16 bit little endian.
+    S16Le,
+    /// Signed 24 bit little endian, packed in 3 bytes.
+    S24Le,
+    /// Signed 32 bit little endian.
+    S32Le,
+    /// 32 bit float little endian.
+    F32Le,
+    /// 64 bit float little endian.
+    F64Le,
+    /// G.711 mu-law.
+    MuLaw,
+    /// G.711 A-law.
+    ALaw,
+}
+
+impl PcmFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            PcmFormat::U8 | PcmFormat::MuLaw | PcmFormat::ALaw => 1,
+            PcmFormat::S16Le => 2,
+            PcmFormat::S24Le => 3,
+            PcmFormat::S32Le | PcmFormat::F32Le => 4,
+            PcmFormat::F64Le => 8,
+        }
+    }
+}
+
+/// Declared layout of a raw PCM input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmSpec {
+    pub format: PcmFormat,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl PcmSpec {
+    pub fn new(format: PcmFormat, sample_rate: u32, channels: u16) -> Self {
+        Self { format, sample_rate, channels }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.format.bytes_per_sample() * self.channels as usize
+    }
+}
+
+/// Decodes a G.711 mu-law sample to 16 bit linear PCM.
+pub fn mulaw_to_linear(value: u8) -> i16 {
+    let value = !value;
+    let exponent = (value >> 4) & 0x07;
+    let mantissa = (value & 0x0f) as i32;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if value & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+/// Decodes a G.711 A-law sample to 16 bit linear PCM.
+pub fn alaw_to_linear(value: u8) -> i16 {
+    let value = value ^ 0x55;
+    let exponent = (value >> 4) & 0x07;
+    let mantissa = (value & 0x0f) as i32;
+    let magnitude = if exponent == 0 {
+        (mantissa << 4) + 8
+    } else {
+        ((mantissa << 4) + 0x108) << (exponent - 1)
+    };
+    // the sign bit is set for positive values in A-law
+    if value & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+/// Decodes whole frames of `data` and appends them to `out`, interleaved.
+fn decode_frames(spec: &PcmSpec, data: &[u8], out: &mut Vec<f32>) {
+    let bps = spec.format.bytes_per_sample();
+    for frame in data.chunks_exact(spec.frame_size()) {
+        for sample in frame.chunks_exact(bps) {
+            out.push(match spec.format {
+                PcmFormat::U8 => (sample[0] as f32 - 128.0) / 128.0,
+                PcmFormat::S16Le => i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+                PcmFormat::S24Le => i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) as f32 / 2147483648.0,
+                PcmFormat::S32Le => i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32 / 2147483648.0,
+                PcmFormat::F32Le => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+                PcmFormat::F64Le => f64::from_le_bytes(sample.try_into().unwrap()) as f32,
+                PcmFormat::MuLaw => mulaw_to_linear(sample[0]) as f32 / 32768.0,
+                PcmFormat::ALaw => alaw_to_linear(sample[0]) as f32 / 32768.0,
+            });
+        }
+    }
+}
+
+/// Feeds headerless PCM from `reader` into the ring buffers, split into 16 kHz mono
+/// streams according to `config.channels`, from `config.start` to `config.end`.
+/// Doesn't need ffmpeg.
+pub fn process_pcm<R: Read>(mut reader: R, spec: PcmSpec, opener: StreamOpener, config: &SessionConfig) -> Result<(), WhisperError> {
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        return Err(WhisperError::Decode(format!("invalid pcm spec: {:?}", spec)));
+    }
+    let frame_size = spec.frame_size();
+    let mut splitter = ChannelSplitter::open(config.channels, spec.channels as usize, spec.sample_rate,
+                                             config.resampler.sinc_quality(), &config.preprocess, OverflowPolicy::Block,
+                                             opener)?;
+
+    let mut raw = vec![0u8; READ_FRAMES * frame_size];
+    // bytes of an incomplete frame left over from the previous read
+    let mut pending = 0usize;
+    let mut decoded = Vec::with_capacity(READ_FRAMES * spec.channels as usize);
+    let mut frames_read = 0u64;
+    let to_frames = |time: Duration| (time.as_secs_f64() * spec.sample_rate as f64) as u64;
+    let first_frame = config.start.map_or(0, to_frames);
+    let end_frame = config.end.map_or(u64::MAX, to_frames);
+    let mut reported_position = Duration::ZERO;
+    loop {
+        let n = match reader.read(&mut raw[pending..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let available = pending + n;
+        let whole = available - available % frame_size;
+
+        decoded.clear();
+        decode_frames(&spec, &raw[..whole], &mut decoded);
+        raw.copy_within(whole..available, 0);
+        pending = available - whole;
+        let block_start = frames_read;
+        frames_read += (whole / frame_size) as u64;
+
+        // keep the part of the block between `start` and `end`
+        let from = first_frame.clamp(block_start, frames_read) - block_start;
+        let to = end_frame.clamp(block_start, frames_read) - block_start;
+        let channels = spec.channels as usize;
+        splitter.write(&decoded[from as usize * channels..to as usize * channels])?;
+
+        let position = Duration::from_micros(frames_read * 1_000_000 / spec.sample_rate as u64);
+        if position >= reported_position + DECODE_PROGRESS_STEP {
+            config.progress.report(ProgressEvent::Decode { position, duration: None });
+            reported_position = position;
+        }
+        if frames_read >= end_frame {
+            break;
+        }
+    }
+    if pending != 0 && frames_read < end_frame {
+        debug!("dropping {} bytes of an incomplete trailing frame", pending);
+    }
+    splitter.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+    use super::*;
+
+    // reference values of the ITU-T G.711 tables
+    #[test]
+    fn g711_tables() {
+        for (code, mulaw, alaw) in [
+            (0x00, -32124, -5504),
+            (0x7f, 0, -848),
+            (0x80, 32124, 5504),
+            (0xff, 0, 848),
+            (0xd5, 716, 8),
+            (0x55, -716, -8),
+            (0x2a, -5372, -32256),
+        ] {
+            assert_eq!(mulaw_to_linear(code), mulaw, "mu-law {:#04x}", code);
+            assert_eq!(alaw_to_linear(code), alaw, "A-law {:#04x}", code);
+        }
+    }
+
+    fn decode(format: PcmFormat, data: &[u8]) -> Vec<f32> {
+        let mut out = Vec::new();
+        decode_frames(&PcmSpec::new(format, 16000, 1), data, &mut out);
+        out
+    }
+
+    #[test]
+    fn linear_formats() {
+        assert_eq!(decode(PcmFormat::S16Le, &[0x00, 0x80, 0x00, 0x40, 0xff, 0xff]), vec![-1.0, 0.5, -1.0 / 32768.0]);
+        assert_eq!(decode(PcmFormat::S24Le, &[0x00, 0x00, 0x80, 0x00, 0x00, 0xc0, 0x01, 0x00, 0x00]),
+                   vec![-1.0, -0.5, 1.0 / 8388608.0]);
+        let floats: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|x| x.to_le_bytes()).collect();
+        assert_eq!(decode(PcmFormat::F32Le, &floats), vec![0.25, -0.75]);
+        // a partial sample is left for the next read
+        assert_eq!(decode(PcmFormat::S16Le, &[0x00, 0x40, 0x00]), vec![0.5]);
+    }
+
+    // hands out at most `step` bytes per read
+    struct Trickle {
+        data: Cursor<Vec<u8>>,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.step);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        // stereo s16le, the left channel counts up, the right one down
+        let frames = 1000i16;
+        let mut data: Vec<u8> = (0..frames).flat_map(|i| [i * 8, -i * 8]).flat_map(|x| x.to_le_bytes()).collect();
+        // an incomplete trailing frame
+        data.extend_from_slice(&[1, 2, 3]);
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let config = SessionConfig { channels: crate::ChannelPolicy::Select(0), ..Default::default() };
+        let spec = PcmSpec::new(PcmFormat::S16Le, 16000, 2);
+        process_pcm(Trickle { data: Cursor::new(data), step: 7 }, spec, StreamOpener::new(16000, opened_tx), &config).unwrap();
+        let streams = opened_rx.recv().unwrap().drain();
+        let expected: Vec<f32> = (0..frames).map(|i| (i * 8) as f32 / 32768.0).collect();
+        assert_eq!(streams, vec![expected]);
+    }
+}
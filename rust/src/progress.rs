@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use crate::transcript::Segment;
+
+/// Minimal decode position advance between two reported `ProgressEvent::Decode`.
+pub const DECODE_PROGRESS_STEP: Duration = Duration::from_secs(1);
+
+/// Progress events emitted while a transcription runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// The decoder reached `position` in the input. `duration` is the container
+    /// duration, if the container reports one.
+    Decode { position: Duration, duration: Option<Duration> },
+    /// whisper reported `percent` progress on the chunk starting at `chunk_start`.
+    Inference { chunk_start: Duration, chunk_len: Duration, percent: i32 },
+    /// whisper emitted `count` new segments, `total` segments so far.
+    Segments { count: usize, total: usize },
+    /// A capture device delivered audio faster than it could be transcribed and
+    /// `dropped` of it was lost, `total` since the capture started.
+    Overrun { dropped: Duration, total: Duration },
+    /// A transcribed segment, as it will appear in `Transcript::segments`.
+    /// This is the only way to get the text of a live session.
+    Segment(Segment),
+}
+
+pub type ProgressCallback = dyn Fn(ProgressEvent) + Send + Sync;
+
+/// Delivers `ProgressEvent`s to a callback. Cloning is cheap, every clone reports
+/// to the same callback.
+#[derive(Clone, Default)]
+pub struct ProgressReporter {
+    callback: Option<Arc<ProgressCallback>>,
+}
+
+impl ProgressReporter {
+    pub fn new<F>(callback: F) -> Self
+        where F: Fn(ProgressEvent) + Send + Sync + 'static
+    {
+        Self { callback: Some(Arc::new(callback)) }
+    }
+
+    /// Creates a reporter that forwards every event into `sender`.
+    /// Events are dropped once the receiving side hangs up.
+    pub fn from_sender(sender: Sender<ProgressEvent>) -> Self {
+        let sender = std::sync::Mutex::new(sender);
+        Self::new(move |event| {
+            let _ = sender.lock().unwrap().send(event);
+        })
+    }
+
+    /// Creates a reporter that drops every event.
+    pub fn disabled() -> Self {
+        Self { callback: None }
+    }
+
+    pub fn report(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.callback {
+            callback(event);
+        }
+    }
+}
+
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("enabled", &self.callback.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::progress::{ProgressEvent, ProgressReporter};
+
+    #[test]
+    fn sender_receives_events_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let reporter = ProgressReporter::from_sender(tx);
+        let events = vec![
+            ProgressEvent::Decode { position: Duration::from_secs(1), duration: Some(Duration::from_secs(11)) },
+            ProgressEvent::Inference { chunk_start: Duration::ZERO, chunk_len: Duration::from_secs(3), percent: 10 },
+            ProgressEvent::Segments { count: 2, total: 2 },
+            ProgressEvent::Inference { chunk_start: Duration::ZERO, chunk_len: Duration::from_secs(3), percent: 100 },
+        ];
+        let clone = reporter.clone();
+        for (i, event) in events.iter().enumerate() {
+            // clones report to the same receiver
+            if i % 2 == 0 { reporter.report(event.clone()) } else { clone.report(event.clone()) }
+        }
+        drop((reporter, clone));
+        assert_eq!(rx.iter().collect::<Vec<_>>(), events);
+    }
+
+    #[test]
+    fn hung_up_receiver_is_ignored() {
+        let (tx, rx) = mpsc::channel();
+        drop(rx);
+        ProgressReporter::from_sender(tx).report(ProgressEvent::Segments { count: 1, total: 1 });
+    }
+}
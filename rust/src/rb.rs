@@ -1,18 +1,20 @@
+use std::cell::UnsafeCell;
 use std::cmp;
+use std::collections::VecDeque;
 use std::fmt;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
-use crate::accel::convert_pcm16_to_f32;
+use std::ops::{Deref, Range};
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use crate::accel::{init_kernels, Sample};
+use crate::clock::{SampleClock, Timeline, TimelineMark};
 
 /// Managment interface for the ring buffer.
 pub trait RB {
-    /// Resets the whole buffer to the default value of type `T`.
-    /// The buffer is empty after this call.
-    fn clear(&self);
-    /// Creates a *producer* view inside the buffer.
+    /// Creates the *producer* view inside the buffer. Panics if it was created before.
     fn producer(&self) -> Producer;
-    /// Creates a *consumer* view inside the buffer.
+    /// Creates a *consumer* view inside the buffer. Panics if all of them were created before.
     fn consumer(&self) -> Consumer;
 }
 
@@ -32,15 +34,37 @@ pub trait RbInspector {
     /// Returns whether the ring buffer is closed
     fn is_closed(&self) -> bool;
     fn close(&self);
+    /// Samples the producer dropped to make room for newer ones, see `OverflowPolicy::DropOldest`.
+    fn dropped_oldest(&self) -> u64;
+    /// Samples the producer dropped because they didn't fit, see `OverflowPolicy::DropNewest`.
+    fn dropped_newest(&self) -> u64;
+}
+
+/// What `RbProducer::write` does when the samples don't fit in the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait for the consumers, fine for files but a real-time input overruns meanwhile.
+    #[default]
+    Block,
+    /// Drop the oldest samples that weren't read yet. Consumers behind them get
+    /// `RbError::Dropped` and continue after the gap. The samples of a view that is
+    /// still being read aren't dropped, what doesn't fit then is dropped as with `DropNewest`.
+    DropOldest,
+    /// Drop the samples that don't fit.
+    DropNewest,
+    /// Fail with `RbError::Full` and write nothing.
+    Error,
 }
 
 /// Defines *write* methods for a producer view.
+///
+/// They take any `Sample` type and convert it to the f32 samples in the buffer.
 pub trait RbProducer {
     /// Works analog to `write` but blocks until there are free slots in the ring buffer.
     /// The number of actual blocks written is returned in the `Option` value.
     ///
     /// Returns `None` if the given slice has zero length.
-    fn write_blocking(&self, data: &[i16]) -> Result<Option<usize>>;
+    fn write_blocking<S: Sample>(&self, data: &[S]) -> Result<Option<usize>>;
     /// Works analog to `write_blocking` but eventually returns if the specified timeout is reached.
     /// The number of actual blocks written is returned in the `Ok(Option)` value.
     ///
@@ -49,17 +73,56 @@ pub trait RbProducer {
     /// Possible errors:
     ///
     /// - `RbError::TimedOut`
-    fn write_blocking_timeout(&self, data: &[i16], timeout: Duration) -> Result<Option<usize>>;
-    fn write_ext_blocking(&self, data: &[i16]) -> Result<()>;
+    fn write_blocking_timeout<S: Sample>(&self, data: &[S], timeout: Duration) -> Result<Option<usize>>;
+    fn write_ext_blocking<S: Sample>(&self, data: &[S]) -> Result<()>;
+    /// Writes all of `data` or handles what doesn't fit as the `OverflowPolicy` of the
+    /// producer says. Returns the number of samples written.
+    fn write<S: Sample>(&self, data: &[S]) -> Result<usize>;
+    /// Works analog to `write`, `pts` is the time of the first sample in the source media.
+    /// Records a `TimelineMark` where it doesn't continue the samples before, see `ReadView::timeline`.
+    fn write_at<S: Sample>(&self, data: &[S], pts: Duration) -> Result<usize>;
     fn close(&self);
 }
 
 /// Defines *read* methods for a consumer view.
+///
+/// Positions are absolute: the number of samples written before, counted from the start.
+/// Times are relative to the first sample, see `clock` for the conversions.
+/// Peeked samples stay in the buffer until they are committed, so they can be peeked again.
 pub trait RbConsumer {
-    fn peek_ext(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange>;
-    fn peek_blocking(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange>;
-    fn peek_time_range(&self, start: usize, end: usize, data: &mut [f32]) -> Result<SampleRange>;
-    fn commit_read(&self, cnt: usize);
+    /// Converts between positions and times of this buffer.
+    fn clock(&self) -> SampleClock;
+    /// Peeks `cnt` samples from `pos` on.
+    ///
+    /// Possible errors:
+    ///
+    /// - `RbError::Again` if fewer samples are available and the buffer isn't closed
+    /// - `RbError::EOF` if the buffer is closed and has no samples from `pos` on
+    /// - `RbError::Released` if samples from `pos` on were committed already
+    /// - `RbError::Dropped` if the producer dropped samples from `pos` on
+    ///
+    /// A closed buffer returns what is left, a shorter view with `is_eof` set.
+    fn peek(&mut self, pos: usize, cnt: usize) -> Result<ReadView<'_>>;
+    /// Works analog to `peek` but blocks until the samples are available or the buffer is closed.
+    fn peek_blocking(&mut self, pos: usize, cnt: usize) -> Result<ReadView<'_>>;
+    /// Peeks the samples between two times. Works analog to `peek`, and fails with
+    /// `RbError::InvalidRange` if the range ends before it starts or doesn't fit in the buffer.
+    fn read_time(&mut self, range: Range<Duration>) -> Result<ReadView<'_>>;
+    /// Peeks the samples between two times in milliseconds, see `read_time`.
+    fn read_ms(&mut self, range: Range<u64>) -> Result<ReadView<'_>> {
+        self.read_time(Duration::from_millis(range.start)..Duration::from_millis(range.end))
+    }
+    /// The sample playing at `time`. Fails like `peek` of a single sample.
+    fn sample_at(&mut self, time: Duration) -> Result<f32> {
+        let pos = self.clock().position(time);
+        let view = self.peek(pos, 1)?;
+        Ok(view.as_slices().0[0])
+    }
+    /// Releases the samples before `pos` to the producer. Fails with `RbError::Unwritten`
+    /// if `pos` is past the samples written so far.
+    fn commit_read(&mut self, pos: usize) -> Result<()>;
+    /// Releases all the samples written so far. The buffer is empty after this call.
+    fn clear(&mut self);
 }
 
 /// Ring buffer errors.
@@ -69,7 +132,15 @@ pub enum RbError {
     Empty,
     TimedOut,
     Again,
-    EOF(SampleRange),
+    EOF,
+    /// The samples from `pos` on were committed, the buffer only holds them from `read` on.
+    Released { pos: usize, read: usize },
+    /// The producer dropped the samples from `pos` to `resume` to make room, reading goes on at `resume`.
+    Dropped { pos: usize, resume: usize },
+    /// A time range that ends before it starts, or is longer than the buffer can hold.
+    InvalidRange { start: Duration, end: Duration },
+    /// A commit up to `pos`, past the `written` samples.
+    Unwritten { pos: usize, written: usize },
 }
 impl fmt::Display for RbError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -78,7 +149,13 @@ impl fmt::Display for RbError {
             RbError::Empty => write!(f, "Buffer is empty"),
             RbError::TimedOut => write!(f, "Timed out waiting for available slots"),
             RbError::Again => write!(f, "Try again"),
-            RbError::EOF(_) => write!(f, "End of data"),
+            RbError::EOF => write!(f, "End of data"),
+            RbError::Released { pos, read } =>
+                write!(f, "Samples from {} on were released, the buffer starts at {}", pos, read),
+            RbError::Dropped { pos, resume } => write!(f, "Samples {} to {} were dropped", pos, resume),
+            RbError::InvalidRange { start, end } => write!(f, "Invalid time range {:?}..{:?}", start, end),
+            RbError::Unwritten { pos, written } =>
+                write!(f, "Can't commit up to {}, only {} samples were written", pos, written),
         }
     }
 }
@@ -86,143 +163,320 @@ impl fmt::Display for RbError {
 /// Result type used inside the module.
 pub type Result<T> = std::result::Result<T, RbError>;
 
-#[derive(Debug)]
-pub enum SampleRange {
-    Adjacent(* const f32, usize),
-    NonAdjacent(usize),
-    EofEmpty,
+/// Samples peeked from the buffer, in two parts if they wrap around its end.
+///
+/// The view borrows the consumer, so the samples can't be committed, and the producer
+/// can't overwrite or drop them, while it lives. Dropping it leaves them in the buffer,
+/// `commit` releases them.
+pub struct ReadView<'a> {
+    consumer: &'a mut Consumer,
+    pos: usize,
+    first: &'a [f32],
+    second: &'a [f32],
+    eof: bool,
 }
 
-struct Inspector {
-    pub gpos: Arc<AtomicUsize>,
-    read_pos: Arc<AtomicUsize>,
-    write_pos: Arc<AtomicUsize>,
-    size: usize,
-    closed: Arc<AtomicBool>,
-}
+impl<'a> ReadView<'a> {
+    /// Absolute position of the first sample.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
 
-impl Inspector {
-    #[allow(dead_code)]
-    fn show_state(&self, owner: &str) {
-        println!("[{}]: read_pos: {}, write_pos: {}, slots_free: {}, count: {}",
-                 owner,
-                 self.read_pos.load(Ordering::Relaxed),
-                 self.write_pos.load(Ordering::Relaxed),
-                 self.slots_free(),
-                 self.count());
+    pub fn len(&self) -> usize {
+        self.first.len() + self.second.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True if the buffer is closed and nothing comes after this view.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// The samples, split where they wrap around the end of the buffer.
+    pub fn as_slices(&self) -> (&[f32], &[f32]) {
+        (self.first, self.second)
+    }
+
+    /// The samples in one slice, copied into `scratch` only if they wrap around.
+    pub fn contiguous<'b>(&'b self, scratch: &'b mut Vec<f32>) -> &'b [f32] {
+        if self.second.is_empty() {
+            return self.first;
+        }
+        scratch.clear();
+        scratch.extend_from_slice(self.first);
+        scratch.extend_from_slice(self.second);
+        scratch
+    }
+
+    /// Source media times of the samples, from the timestamps the producer wrote them with.
+    pub fn timeline(&self) -> Timeline {
+        let shared = &self.consumer.shared;
+        let marks = shared.marks.lock().unwrap();
+        // the mark the view starts in, and the ones inside it
+        let first = marks.iter().rposition(|mark| mark.pos <= self.pos).unwrap_or(0);
+        let end = self.pos + self.len();
+        Timeline::new(shared.clock, marks.iter().skip(first).take_while(|mark| mark.pos < end).copied().collect())
+    }
+
+    /// Releases the samples of the view to the producer, returns the position after them.
+    pub fn commit(self) -> usize {
+        let end = self.pos + self.len();
+        // the view only holds written samples
+        self.consumer.release(end);
+        end
     }
 }
 
-/// A *thread-safe* Single-Producer-Single-Consumer RingBuffer
-///
-/// - blocking and non-blocking IO
-/// - mutually exclusive access for producer and consumer
-/// - no use of `unsafe`
-/// - never under- or overflows
-///
-/// ```
-/// use std::thread;
-/// use rb::*;
-///
-/// let rb = SpscRb::new(1024);
-/// let (prod, cons) = (rb.producer(), rb.consumer());
-/// thread::spawn(move || {
-///     let gen = || {(-16..16+1).cycle().map(|x| x as f32/16.0)};
-///     loop {
-///         let data = gen().take(32).collect::<Vec<f32>>();
-///         prod.write(&data).unwrap();
-///     }
-/// });
-/// let mut data = Vec::with_capacity(1024);
-/// let mut buf = [0.0f32; 256];
-/// while data.len() < 1024 {
-///     let cnt = cons.read_blocking(&mut buf).unwrap();
-///     data.extend_from_slice(&buf[..cnt]);
-/// }
-/// ```
-pub struct SpscRb {
-    buf: Arc<Mutex<Vec<f32>>>,
-    inspector: Arc<Inspector>,
-    slots_free: Arc<Condvar>,
-    data_available: Arc<Condvar>,
+impl Drop for ReadView<'_> {
+    fn drop(&mut self) {
+        let shared = &self.consumer.shared;
+        shared.cursors[self.consumer.cursor].pinned.store(UNPINNED, Ordering::Release);
+    }
 }
 
-impl SpscRb {
-    #[allow(dead_code)]
-    pub fn new(size: usize) -> Self {
-        let (read_pos, write_pos) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
-        SpscRb {
-            buf: Arc::new(Mutex::new(vec![f32::default(); size + 1])),
-            slots_free: Arc::new(Condvar::new()),
-            data_available: Arc::new(Condvar::new()),
-            // the additional element is used to distinct between empty and full state
-            inspector: Arc::new(Inspector {
-                gpos: Arc::new(AtomicUsize::new(0)),
-                read_pos,
-                write_pos,
-                size: size + 1,
-                closed: Arc::new(AtomicBool::new(false)),
-            }),
-        }
+impl fmt::Debug for ReadView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadView")
+            .field("pos", &self.pos)
+            .field("len", &self.len())
+            .field("wrapped", &!self.second.is_empty())
+            .field("eof", &self.eof)
+            .finish()
     }
 }
 
-impl RB for SpscRb {
-    fn clear(&self) {
-        let mut buf = self.buf.lock().unwrap();
-        buf.iter_mut().map(|_| f32::default()).count();
-        self.inspector.read_pos.store(0, Ordering::Relaxed);
-        self.inspector.write_pos.store(0, Ordering::Relaxed);
+// Keeps the counters of the producer and the consumer on separate cache lines.
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
     }
+}
 
-    fn producer(&self) -> Producer {
-        Producer {
-            buf: self.buf.clone(),
-            inspector: self.inspector.clone(),
-            slots_free: self.slots_free.clone(),
-            data_available: self.data_available.clone(),
+/// Parks one side of the buffer until the other side made progress.
+#[derive(Default)]
+struct Waiter {
+    waiting: AtomicBool,
+    thread: Mutex<Option<Thread>>,
+}
+
+impl Waiter {
+    /// Waits until `ready` returns true or `deadline` passes, `None` waits forever.
+    /// Returns the last result of `ready`.
+    fn wait(&self, deadline: Option<Instant>, ready: impl Fn() -> bool) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            *self.thread.lock().unwrap() = Some(thread::current());
+            self.waiting.store(true, Ordering::SeqCst);
+            // pairs with the fence in `notify`: either the other side sees `waiting`
+            // and unparks this thread, or `ready` sees what it changed
+            fence(Ordering::SeqCst);
+            if !ready() {
+                match deadline {
+                    None => thread::park(),
+                    Some(deadline) => thread::park_timeout(deadline.saturating_duration_since(Instant::now())),
+                }
+            }
+            self.waiting.store(false, Ordering::Relaxed);
         }
     }
 
-    fn consumer(&self) -> Consumer {
-        Consumer {
-            buf: self.buf.clone(),
-            inspector: self.inspector.clone(),
-            slots_free: self.slots_free.clone(),
-            data_available: self.data_available.clone(),
+    /// Unparks the thread in `wait`, if there is one.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) {
+            if let Some(thread) = self.thread.lock().unwrap().as_ref() {
+                thread.unpark();
+            }
         }
     }
 }
 
-impl RbInspector for SpscRb {
-    fn is_empty(&self) -> bool {
-        self.inspector.is_empty()
+// `read` of a dropped consumer, which no longer holds back the producer
+const DETACHED: usize = usize::MAX;
+// `pinned` of a consumer without a view
+const UNPINNED: usize = usize::MAX;
+// timestamps closer than this to where the samples before end continue them
+const PTS_TOLERANCE: Duration = Duration::from_millis(30);
+
+/// Read position of a consumer.
+struct Cursor {
+    read: AtomicUsize,
+    // start of the view the consumer holds, the producer can't drop the samples from here on
+    pinned: AtomicUsize,
+    // the producer dropped the samples up to here
+    dropped_to: AtomicUsize,
+}
+
+impl Cursor {
+    fn new() -> Self {
+        Cursor { read: AtomicUsize::new(0), pinned: AtomicUsize::new(UNPINNED), dropped_to: AtomicUsize::new(0) }
     }
-    fn is_full(&self) -> bool {
-        self.inspector.is_full()
+}
+
+struct Shared {
+    buf: Box<[UnsafeCell<f32>]>,
+    clock: SampleClock,
+    // Monotonic sample counters, a slot is at `counter % buf.len()`. Only the producer
+    // stores `written`, with release after filling the slots. Each consumer moves its
+    // own `read` forward once it's done with the slots, the producer moves it only
+    // to drop the oldest samples.
+    written: CachePadded<AtomicUsize>,
+    cursors: Box<[CachePadded<Cursor>]>,
+    closed: AtomicBool,
+    dropped_oldest: AtomicU64,
+    dropped_newest: AtomicU64,
+    // where the source timeline of the samples jumps, written by the producer before the samples
+    marks: Mutex<VecDeque<TimelineMark>>,
+    // only one producer and one consumer view per cursor may exist
+    has_producer: AtomicBool,
+    consumers: AtomicUsize,
+    live_consumers: AtomicUsize,
+    // each consumer waits for data, the producer for free slots
+    data_available: Box<[Waiter]>,
+    slots_free: Waiter,
+}
+
+// The producer only touches the slots from `written` to the slowest `read + capacity`,
+// each consumer the ones from its `read` to `written`; the counters hand the slots over
+// between them. Dropping the oldest samples, the producer doesn't take back the slots
+// from `pinned` on.
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn new(size: usize, clock: SampleClock, consumers: usize) -> Self {
+        assert!(consumers > 0, "a ring buffer needs a consumer");
+        Shared {
+            buf: (0..size).map(|_| UnsafeCell::new(0.0)).collect(),
+            clock,
+            written: CachePadded(AtomicUsize::new(0)),
+            cursors: (0..consumers).map(|_| CachePadded(Cursor::new())).collect(),
+            closed: AtomicBool::new(false),
+            dropped_oldest: AtomicU64::new(0),
+            dropped_newest: AtomicU64::new(0),
+            marks: Mutex::new(VecDeque::new()),
+            has_producer: AtomicBool::new(false),
+            consumers: AtomicUsize::new(0),
+            live_consumers: AtomicUsize::new(0),
+            data_available: (0..consumers).map(|_| Waiter::default()).collect(),
+            slots_free: Waiter::default(),
+        }
     }
-    fn capacity(&self) -> usize {
-        self.inspector.capacity()
+
+    fn producer(self: &Arc<Self>) -> Producer {
+        assert!(!self.has_producer.swap(true, Ordering::AcqRel), "the ring buffer already has a producer");
+        // before the producer converts anything
+        init_kernels();
+        Producer { shared: self.clone(), overflow: OverflowPolicy::Block }
     }
-    fn slots_free(&self) -> usize {
-        self.inspector.slots_free()
+
+    fn consumer(self: &Arc<Self>) -> Consumer {
+        let cursor = self.consumers.fetch_add(1, Ordering::AcqRel);
+        assert!(cursor < self.cursors.len(),
+                "the ring buffer already has a consumer for each of its {} cursors", self.cursors.len());
+        self.live_consumers.fetch_add(1, Ordering::AcqRel);
+        Consumer { shared: self.clone(), cursor }
     }
-    fn count(&self) -> usize {
-        self.inspector.count()
+
+    /// Cursor of the consumer furthest behind, the producer can't write past it.
+    fn slowest_read(&self) -> usize {
+        self.cursors.iter().map(|cursor| cursor.read.load(Ordering::Acquire)).min().unwrap_or(0)
     }
-    fn is_closed(&self) -> bool {
-        self.inspector.is_closed()
+
+    /// Moves the consumers behind `target` up to it, dropping the samples they didn't read.
+    /// Returns where the producer may write up to without touching the slots of a view.
+    fn drop_oldest(&self, target: usize) -> usize {
+        let slowest = self.slowest_read();
+        if slowest < target {
+            for cursor in self.cursors.iter() {
+                // not into a view, as far as it can be seen yet
+                let target = cmp::min(target, cursor.pinned.load(Ordering::Acquire));
+                if cursor.read.load(Ordering::Acquire) < target {
+                    // before `read`, a consumer seeing the new `read` knows the samples were dropped
+                    cursor.dropped_to.fetch_max(target, Ordering::Release);
+                    cursor.read.fetch_max(target, Ordering::SeqCst);
+                }
+            }
+            let dropped = self.slowest_read().saturating_sub(slowest);
+            self.dropped_oldest.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+        // pairs with pinning in `peek`: either this sees the pin, or the consumer sees the new `read`
+        let free_from = self.cursors.iter()
+            .map(|cursor| cmp::min(cursor.read.load(Ordering::SeqCst), cursor.pinned.load(Ordering::SeqCst)))
+            .min()
+            .unwrap_or(0);
+        free_from.saturating_add(self.capacity())
     }
+
+    /// Records that the next sample written is at `pts`, unless that continues the last mark.
+    fn mark(&self, pts: Duration) {
+        let pos = self.written.load(Ordering::Relaxed);
+        let mut marks = self.marks.lock().unwrap();
+        if let Some(last) = marks.back() {
+            let expected = last.pts + self.clock.time(pos - last.pos);
+            if expected.abs_diff(pts) <= PTS_TOLERANCE {
+                return;
+            }
+        }
+        // only the mark the slowest consumer is in and the ones after it are still needed
+        let read = self.slowest_read();
+        while marks.len() > 1 && marks[1].pos <= read {
+            marks.pop_front();
+        }
+        if marks.back().is_some_and(|last| last.pos == pos) {
+            marks.pop_back();
+        }
+        marks.push_back(TimelineMark { pos, pts });
+    }
+
+    /// Copies `data` into the slots from `written` on and hands them to the consumers.
+    /// Only for the producer, and only as long as the slots are free.
+    unsafe fn fill<S: Sample>(&self, data: &[S]) {
+        let written = self.written.load(Ordering::Relaxed);
+        let first = cmp::min(data.len(), self.buf.len() - written % self.buf.len());
+        S::convert_to_f32(&data[..first], std::slice::from_raw_parts_mut(self.slot(written), first));
+        S::convert_to_f32(&data[first..], std::slice::from_raw_parts_mut(self.slot(0), data.len() - first));
+        self.written.store(written + data.len(), Ordering::Release);
+        self.data_available.iter().for_each(Waiter::notify);
+    }
+
+    fn slot(&self, pos: usize) -> *mut f32 {
+        UnsafeCell::raw_get(self.buf[..].as_ptr().wrapping_add(pos % self.buf.len()))
+    }
+
+    /// The `cnt` values from `pos` on, split where they wrap around.
+    ///
+    /// Only for the consumer, for values between `read` and `written`, and only as long
+    /// as it doesn't commit them.
+    unsafe fn slices<'a>(&self, pos: usize, cnt: usize) -> (&'a [f32], &'a [f32]) {
+        let start = pos % self.buf.len();
+        let first = cmp::min(cnt, self.buf.len() - start);
+        (std::slice::from_raw_parts(self.slot(pos), first), std::slice::from_raw_parts(self.slot(0), cnt - first))
+    }
+
     fn close(&self) {
-        self.inspector.close();
-        self.data_available.notify_one();
+        self.closed.store(true, Ordering::Release);
+        self.data_available.iter().for_each(Waiter::notify);
+        self.slots_free.notify();
     }
 }
 
-impl RbInspector for Inspector {
+impl RbInspector for Shared {
     #[inline(always)]
     fn is_empty(&self) -> bool {
-        self.slots_free() == self.capacity()
+        self.count() == 0
     }
 
     #[inline(always)]
@@ -232,119 +486,240 @@ impl RbInspector for Inspector {
 
     #[inline(always)]
     fn capacity(&self) -> usize {
-        self.size - 1
+        self.buf.len()
     }
 
     #[inline(always)]
     fn slots_free(&self) -> usize {
-        let wr_pos = self.write_pos.load(Ordering::Relaxed);
-        let re_pos = self.read_pos.load(Ordering::Relaxed);
-        if wr_pos < re_pos {
-            re_pos - wr_pos - 1
-        } else {
-            self.capacity() - wr_pos + re_pos
-        }
+        self.capacity() - self.count()
     }
 
     #[inline(always)]
     fn count(&self) -> usize {
-        self.capacity() - self.slots_free()
+        // `read` first, it never passes `written`
+        let read = self.slowest_read();
+        self.written.load(Ordering::Acquire) - read
     }
 
     #[inline(always)]
     fn is_closed(&self) -> bool {
-        self.closed.load(Ordering::Relaxed)
+        self.closed.load(Ordering::Acquire)
     }
 
     #[inline(always)]
     fn close(&self) {
-        self.closed.store(true, Ordering::Relaxed);
+        Shared::close(self);
+    }
+
+    fn dropped_oldest(&self) -> u64 {
+        self.dropped_oldest.load(Ordering::Relaxed)
+    }
+
+    fn dropped_newest(&self) -> u64 {
+        self.dropped_newest.load(Ordering::Relaxed)
     }
 }
 
+// the buffers and their views all inspect the shared state
+macro_rules! impl_inspector {
+    ($($ty:ty),*) => {$(
+        impl RbInspector for $ty {
+            fn is_empty(&self) -> bool {
+                self.shared.is_empty()
+            }
+            fn is_full(&self) -> bool {
+                self.shared.is_full()
+            }
+            fn capacity(&self) -> usize {
+                self.shared.capacity()
+            }
+            fn slots_free(&self) -> usize {
+                self.shared.slots_free()
+            }
+            fn count(&self) -> usize {
+                self.shared.count()
+            }
+            fn is_closed(&self) -> bool {
+                self.shared.is_closed()
+            }
+            fn close(&self) {
+                self.shared.close();
+            }
+            fn dropped_oldest(&self) -> u64 {
+                self.shared.dropped_oldest()
+            }
+            fn dropped_newest(&self) -> u64 {
+                self.shared.dropped_newest()
+            }
+        }
+    )*};
+}
+
+impl_inspector!(SpscRb, SpmcRb, Producer, Consumer);
+
+/// A lock-free Single-Producer-Single-Consumer ring buffer of samples, 16 kHz unless
+/// created `with_clock`.
+///
+/// The producer writes any `Sample` type, the consumer peeks f32 samples at absolute positions
+/// and commits them once it's done, so a window can be peeked again before it's released.
+/// Blocking calls park the thread until the other side makes progress.
+pub struct SpscRb {
+    shared: Arc<Shared>,
+}
+
+impl SpscRb {
+    pub fn new(size: usize) -> Self {
+        Self::with_clock(size, SampleClock::WHISPER)
+    }
+
+    /// A buffer of `size` samples at the rate of `clock`.
+    pub fn with_clock(size: usize, clock: SampleClock) -> Self {
+        SpscRb { shared: Arc::new(Shared::new(size, clock, 1)) }
+    }
+}
+
+impl RB for SpscRb {
+    fn producer(&self) -> Producer {
+        self.shared.producer()
+    }
+
+    fn consumer(&self) -> Consumer {
+        self.shared.consumer()
+    }
+}
+
+/// A lock-free Single-Producer-Multi-Consumer ring buffer, so that e.g. VAD, language
+/// detection and transcription read the same samples without copying them.
+///
+/// Every consumer peeks and commits on its own cursor, with the same `RbConsumer` methods
+/// as the consumer of an `SpscRb`. A slot is only reused once all consumers committed it,
+/// so the producer waits for the slowest one; consumers that weren't created yet hold
+/// the buffer from the start. A dropped consumer stops holding it back, dropping the
+/// last one closes the buffer.
+pub struct SpmcRb {
+    shared: Arc<Shared>,
+}
+
+impl SpmcRb {
+    /// A 16 kHz buffer of `size` samples, read by `consumers` consumers.
+    pub fn new(size: usize, consumers: usize) -> Self {
+        Self::with_clock(size, consumers, SampleClock::WHISPER)
+    }
+
+    pub fn with_clock(size: usize, consumers: usize, clock: SampleClock) -> Self {
+        SpmcRb { shared: Arc::new(Shared::new(size, clock, consumers)) }
+    }
+}
+
+impl RB for SpmcRb {
+    fn producer(&self) -> Producer {
+        self.shared.producer()
+    }
+
+    fn consumer(&self) -> Consumer {
+        self.shared.consumer()
+    }
+}
+
+#[allow(dead_code)]
+fn show_state(shared: &Shared, owner: &str) {
+    println!("[{}]: read: {}, written: {}, slots_free: {}, count: {}",
+             owner,
+             shared.slowest_read(),
+             shared.written.load(Ordering::Relaxed),
+             shared.slots_free(),
+             shared.count());
+}
+
 /// Producer view into the ring buffer.
 pub struct Producer {
-    buf: Arc<Mutex<Vec<f32>>>,
-    inspector: Arc<Inspector>,
-    slots_free: Arc<Condvar>,
-    data_available: Arc<Condvar>,
+    shared: Arc<Shared>,
+    overflow: OverflowPolicy,
 }
 
 impl Producer {
+    /// Sets what `write` does with samples that don't fit, `OverflowPolicy::Block` by default.
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn overflow(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
     #[allow(dead_code)]
     pub fn show_state(&self) {
-        self.inspector.show_state("producer");
+        show_state(&self.shared, "producer");
+    }
+}
+
+impl Drop for Producer {
+    /// A dropped producer can't write anymore, let the consumer see EOF.
+    fn drop(&mut self) {
+        RbProducer::close(self);
     }
 }
 
 /// Consumer view into the ring buffer.
 pub struct Consumer {
-    buf: Arc<Mutex<Vec<f32>>>,
-    inspector: Arc<Inspector>,
-    slots_free: Arc<Condvar>,
-    data_available: Arc<Condvar>,
+    shared: Arc<Shared>,
+    // index of its read cursor and waiter
+    cursor: usize,
 }
 
 impl Consumer {
     #[allow(dead_code)]
     pub fn show_state(&self) {
-        self.inspector.show_state("consumer");
+        show_state(&self.shared, "consumer");
+    }
+
+    // releases the samples before `read_end`, which must have been written
+    fn release(&mut self, read_end: usize) {
+        // the producer may have dropped samples past `read_end` already
+        self.shared.cursors[self.cursor].read.fetch_max(read_end, Ordering::AcqRel);
+        self.shared.slots_free.notify();
+    }
+}
+
+impl Drop for Consumer {
+    /// The consumer no longer holds back the producer. Once nobody reads anymore,
+    /// wake up and fail a producer waiting for free slots.
+    fn drop(&mut self) {
+        if self.shared.live_consumers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.close();
+        } else {
+            self.shared.cursors[self.cursor].read.store(DETACHED, Ordering::Release);
+            self.shared.slots_free.notify();
+        }
     }
 }
 
 impl RbProducer for Producer {
-    fn write_blocking(&self, data: &[i16]) -> Result<Option<usize>> {
-        //println!("write_blocking: data.len() = {}", data.len());
-        let ret = self.write_blocking_timeout(data, Duration::MAX);
-        //self.show_state();
-        ret
+    fn write_blocking<S: Sample>(&self, data: &[S]) -> Result<Option<usize>> {
+        self.write_blocking_timeout(data, Duration::MAX)
     }
 
-    fn write_blocking_timeout(&self, data: &[i16], timeout: Duration) -> Result<Option<usize>> {
+    fn write_blocking_timeout<S: Sample>(&self, data: &[S], timeout: Duration) -> Result<Option<usize>> {
         if data.is_empty() {
             return Ok(None);
         }
-
-        let guard = self.buf.lock().unwrap();
-        if self.inspector.is_closed() {
-            return Err(RbError::EOF(SampleRange::EofEmpty));
+        let shared = &*self.shared;
+        let deadline = Instant::now().checked_add(timeout);
+        if !shared.slots_free.wait(deadline, || shared.is_closed() || !shared.is_full()) {
+            return Err(RbError::TimedOut);
         }
-        let mut buf = if self.inspector.is_full() {
-            if timeout == Duration::MAX {
-                // No need to call wait_timeout if the duration is max
-                self.slots_free.wait(guard).unwrap()
-            } else {
-                let (guard, result) = self.slots_free.wait_timeout(guard, timeout).unwrap();
-                if result.timed_out() {
-                    return Err(RbError::TimedOut);
-                }
-                guard
-            }
-        } else {
-            guard
-        };
-
-        let buf_len = buf.len();
-        let data_len = data.len();
-        let wr_pos = self.inspector.write_pos.load(Ordering::Relaxed);
-        let cnt = cmp::min(data_len, self.inspector.slots_free());
-
-        if (wr_pos + cnt) < buf_len {
-            convert_pcm16_to_f32(&data[..cnt], &mut buf[wr_pos..wr_pos + cnt]);
-        } else {
-            let d = buf_len - wr_pos;
-            convert_pcm16_to_f32(&data[..d], &mut buf[wr_pos..]);
-            convert_pcm16_to_f32(&data[d..cnt], &mut buf[..(cnt-d)]);
+        if shared.is_closed() {
+            return Err(RbError::EOF);
         }
-        self.inspector
-            .write_pos
-            .store((wr_pos + cnt) % buf_len, Ordering::Relaxed);
 
-        self.data_available.notify_one();
+        let cnt = cmp::min(data.len(), shared.slots_free());
+        // the slots from `written` to `read + capacity` belong to the producer
+        unsafe { shared.fill(&data[..cnt]) };
         Ok(Some(cnt))
     }
 
-    fn write_ext_blocking(&self, data: &[i16]) -> Result<()> {
+    fn write_ext_blocking<S: Sample>(&self, data: &[S]) -> Result<()> {
         let buf_len = data.len();
         let mut pos = 0usize;
         while let Some(written) = self.write_blocking(&data[pos..])? {
@@ -356,117 +731,453 @@ impl RbProducer for Producer {
         Ok(())
     }
 
+    fn write<S: Sample>(&self, data: &[S]) -> Result<usize> {
+        let shared = &*self.shared;
+        if self.overflow == OverflowPolicy::Block {
+            self.write_ext_blocking(data)?;
+            return Ok(data.len());
+        }
+        if shared.is_closed() {
+            return Err(RbError::EOF);
+        }
+        let cnt = match self.overflow {
+            OverflowPolicy::DropOldest => {
+                // of more than fits, only the newest samples can be kept; they follow the
+                // samples written before without a gap
+                let kept = &data[data.len().saturating_sub(shared.capacity())..];
+                shared.dropped_oldest.fetch_add((data.len() - kept.len()) as u64, Ordering::Relaxed);
+                let written = shared.written.load(Ordering::Relaxed);
+                let limit = shared.drop_oldest((written + kept.len()).saturating_sub(shared.capacity()));
+                let cnt = cmp::min(kept.len(), limit.saturating_sub(written));
+                // the slots up to `limit` were read, dropped or belonged to the producer already
+                unsafe { shared.fill(&kept[..cnt]) };
+                shared.dropped_newest.fetch_add((kept.len() - cnt) as u64, Ordering::Relaxed);
+                return Ok(cnt);
+            }
+            OverflowPolicy::DropNewest => cmp::min(data.len(), shared.slots_free()),
+            OverflowPolicy::Error if data.len() > shared.slots_free() => return Err(RbError::Full),
+            _ => data.len(),
+        };
+        unsafe { shared.fill(&data[..cnt]) };
+        shared.dropped_newest.fetch_add((data.len() - cnt) as u64, Ordering::Relaxed);
+        Ok(cnt)
+    }
+
+    fn write_at<S: Sample>(&self, data: &[S], pts: Duration) -> Result<usize> {
+        self.shared.mark(pts);
+        self.write(data)
+    }
+
     fn close(&self) {
-        self.inspector.close();
-        self.data_available.notify_one();
+        self.shared.close();
     }
 }
 
 impl RbConsumer for Consumer {
+    fn clock(&self) -> SampleClock {
+        self.shared.clock
+    }
 
-    fn peek_ext(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange> {
-        let guard = self.buf.lock().unwrap();
-        let gpos = self.inspector.gpos.load(Ordering::Relaxed);
-        if gpos > pos {
-            panic!("can't read data already read committed")
-        }
-        let re_pos_offset = pos - gpos;
-        let available_cnt = self.inspector.count() - re_pos_offset;
-        let mut req_cnt = data.len();
-        let mut is_tail_partial = false;
-        if available_cnt < req_cnt {
-            if self.inspector.is_closed() {
-                is_tail_partial = true;
-                if available_cnt == 0 {
-                    return Err(RbError::EOF(SampleRange::EofEmpty));
-                }
-                req_cnt = available_cnt;
+    fn peek(&mut self, pos: usize, cnt: usize) -> Result<ReadView<'_>> {
+        let shared = &*self.shared;
+        let cursor = &shared.cursors[self.cursor];
+        // pin before checking `read`, pairs with `drop_oldest`: either the producer sees
+        // the pin and leaves the samples alone, or this sees that they were dropped
+        cursor.pinned.store(pos, Ordering::SeqCst);
+        let read = cursor.read.load(Ordering::SeqCst);
+        // `closed` first, a closed buffer shows everything written before it was closed
+        let closed = shared.is_closed();
+        let available_cnt = shared.written.load(Ordering::Acquire).saturating_sub(pos);
+        let error = if read > pos {
+            if pos < cursor.dropped_to.load(Ordering::Acquire) {
+                Some(RbError::Dropped { pos, resume: read })
             } else {
-                return Err(RbError::Again)
-            }
-        }
-        let buf = guard.as_slice();
-        let buf_len = buf.len();
-        let re_pos = (self.inspector.read_pos.load(Ordering::Relaxed) + re_pos_offset) % buf_len;
-        if (re_pos + req_cnt) < buf_len {
-            //println!("peek_f32_ext: req_cnt = {}, available_cnt = {}", req_cnt, available_cnt);
-            //self.show_state();
-            //data[..req_cnt].copy_from_slice(&buf[re_pos..re_pos + req_cnt]);
-            // if sample range is adjacent to buffer, return a pointer to the buffer
-            if !is_tail_partial {
-                Ok(SampleRange::Adjacent(buf[re_pos..re_pos + req_cnt].as_ptr(), req_cnt))
-            } else {
-                Err(RbError::EOF(SampleRange::Adjacent(buf[re_pos..re_pos + req_cnt].as_ptr(), req_cnt)))
+                Some(RbError::Released { pos, read })
             }
+        } else if available_cnt < cnt && !closed {
+            Some(RbError::Again)
+        } else if available_cnt == 0 && closed {
+            Some(RbError::EOF)
         } else {
-            let d = buf_len - re_pos;
-            //println!("peek_f32_ext: req_cnt = {}, d = {}", req_cnt, d);
-            data[..d].copy_from_slice(&buf[re_pos..]);
-            data[d..].copy_from_slice(&buf[..(req_cnt - d)]);
-            if !is_tail_partial {
-                Ok(SampleRange::NonAdjacent(req_cnt))
-            } else {
-                Err(RbError::EOF(SampleRange::NonAdjacent(req_cnt)))
-            }
+            None
+        };
+        if let Some(e) = error {
+            cursor.pinned.store(UNPINNED, Ordering::Release);
+            return Err(e);
+        }
+        let cnt = cmp::min(cnt, available_cnt);
+        // the slots from its `read` to `written` belong to the consumer, the view borrows it
+        // so they can't be committed while they are read
+        let (first, second) = unsafe { shared.slices(pos, cnt) };
+        Ok(ReadView { consumer: self, pos, first, second, eof: closed && available_cnt == cnt })
+    }
+
+    fn peek_blocking(&mut self, pos: usize, cnt: usize) -> Result<ReadView<'_>> {
+        let shared = &*self.shared;
+        let end = pos + cnt;
+        shared.data_available[self.cursor].wait(None, || shared.is_closed() || shared.written.load(Ordering::Acquire) >= end);
+        self.peek(pos, cnt)
+    }
+
+    fn read_time(&mut self, range: Range<Duration>) -> Result<ReadView<'_>> {
+        let clock = self.shared.clock;
+        let (start, end) = (clock.position(range.start), clock.position(range.end));
+        // a longer range would wait for samples that can never be written
+        if end < start || end - start > self.shared.capacity() {
+            return Err(RbError::InvalidRange { start: range.start, end: range.end });
+        }
+        self.peek(start, end - start)
+    }
+
+    fn commit_read(&mut self, read_end: usize) -> Result<()> {
+        let written = self.shared.written.load(Ordering::Acquire);
+        if read_end > written {
+            return Err(RbError::Unwritten { pos: read_end, written });
         }
+        self.release(read_end);
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        let written = self.shared.written.load(Ordering::Acquire);
+        self.release(written);
     }
+}
 
-    fn peek_blocking(&self, pos: usize, data: &mut [f32]) -> Result<SampleRange> {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(cons: &mut Consumer, block: usize) -> Vec<f32> {
+        let mut out = Vec::new();
         loop {
-            //println!("pos: Peeking at position {}, data.len() = {}", pos, data.len());
-            //self.show_state();
-            match self.peek_ext(pos, data) {
-                Ok(sr) => return Ok(sr),
-                Err(RbError::Again) => continue,
-                Err(RbError::EOF(sr)) => return Err(RbError::EOF(sr)),
-                _ => (),
+            let view = match cons.peek_blocking(out.len(), block) {
+                Ok(view) => view,
+                Err(RbError::EOF) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let (first, second) = view.as_slices();
+            out.extend_from_slice(first);
+            out.extend_from_slice(second);
+            let eof = view.is_eof();
+            view.commit();
+            if eof {
+                break;
             }
-            let guard = self.buf.lock().unwrap();
-            let _buf = self.data_available.wait(guard).unwrap();
         }
+        out
     }
 
-    fn peek_time_range(&self, start: usize, end: usize, data: &mut [f32]) -> Result<SampleRange> {
-        let start_pos = start * 16;
-        let end_pos = end * 16;
-        let guard = self.buf.lock().unwrap();
-        let gpos = self.inspector.gpos.load(Ordering::Relaxed);
-        if gpos > start_pos {
-            panic!("peek_time_range: can't read data already read committed")
+    #[test]
+    fn samples_arrive_in_order_across_threads() {
+        let rb = SpscRb::new(1000);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        let input: Vec<i16> = (0..200_000).map(|i| (i % 65536 - 32768) as i16).collect();
+        let expected: Vec<f32> = input.iter().map(|x| *x as f32 / 32768.0).collect();
+        let writer = thread::spawn(move || {
+            for block in input.chunks(333) {
+                prod.write_ext_blocking(block).unwrap();
+            }
+        });
+        let output = read_all(&mut cons, 777);
+        writer.join().unwrap();
+        assert_eq!(output.len(), expected.len());
+        assert!(output.iter().zip(&expected).all(|(x, y)| (x - y).abs() < 1e-6));
+    }
+
+    #[test]
+    fn partial_tail_is_eof() {
+        let rb = SpscRb::new(100);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[1000i16; 30]).unwrap();
+        drop(prod);
+        let view = cons.peek_blocking(0, 50).unwrap();
+        assert_eq!((view.len(), view.is_eof()), (30, true));
+        assert_eq!(view.commit(), 30);
+        assert!(matches!(cons.peek_blocking(30, 50), Err(RbError::EOF)));
+    }
+
+    #[test]
+    fn uncommitted_view_can_be_peeked_again() {
+        let rb = SpscRb::new(10);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[16384i16; 10]).unwrap();
+        {
+            let view = cons.peek(0, 10).unwrap();
+            assert!(!view.is_eof());
         }
-        let req_cnt = end_pos - start_pos;
-        let available_cnt = self.inspector.count() - (start_pos - gpos);
-        if available_cnt < req_cnt {
-            panic!("peek_time_range: can't read data, not enough")
+        // the dropped view didn't release the slots
+        assert!(matches!(prod.write_blocking_timeout(&[0; 1], Duration::ZERO), Err(RbError::TimedOut)));
+        assert_eq!(cons.peek(0, 10).unwrap().as_slices().0, &[0.5; 10]);
+    }
+
+    #[test]
+    fn full_buffer_times_out() {
+        let rb = SpscRb::new(10);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        assert_eq!(prod.write_blocking(&[0; 16]).unwrap(), Some(10));
+        assert!(matches!(prod.write_blocking_timeout(&[0; 4], Duration::from_millis(10)), Err(RbError::TimedOut)));
+        cons.commit_read(4).unwrap();
+        assert_eq!(prod.write_blocking_timeout(&[0; 8], Duration::ZERO).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn dropped_consumer_wakes_blocked_producer() {
+        let rb = SpscRb::new(10);
+        let (prod, cons) = (rb.producer(), rb.consumer());
+        prod.write_blocking(&[0; 10]).unwrap();
+        let writer = thread::spawn(move || prod.write_blocking(&[0; 10]));
+        thread::sleep(Duration::from_millis(20));
+        drop(cons);
+        assert!(matches!(writer.join().unwrap(), Err(RbError::EOF)));
+    }
+
+    #[test]
+    fn writes_any_sample_type() {
+        let rb = SpscRb::new(10);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[0.25f32]).unwrap();
+        prod.write_ext_blocking(&[-0.5f64]).unwrap();
+        prod.write_ext_blocking(&[i32::MIN / 4]).unwrap();
+        prod.write_ext_blocking(&[192u8]).unwrap();
+        prod.write_ext_blocking(&[-8192i16]).unwrap();
+        assert_eq!(cons.peek(0, 5).unwrap().as_slices().0, &[0.25, -0.5, -0.25, 0.5, -0.25]);
+    }
+
+    #[test]
+    fn timestamp_jumps_are_marked() {
+        let rb = SpscRb::new(16000);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        let ms = Duration::from_millis;
+        prod.write_at(&[0i16; 1600], ms(5000)).unwrap();
+        // continuous, with some jitter
+        prod.write_at(&[0i16; 1600], ms(5110)).unwrap();
+        // 500 ms lost
+        prod.write_at(&[0i16; 1600], ms(5700)).unwrap();
+        prod.write(&[0i16; 1600]).unwrap();
+        {
+            let timeline = cons.peek(0, 6400).unwrap().timeline();
+            assert_eq!(timeline.marks().len(), 2);
+            assert_eq!(timeline.source_time(1600), Some(ms(5100)));
+            assert_eq!(timeline.source_time(4800), Some(ms(5800)));
         }
-        let re_pos = self.inspector.read_pos.load(Ordering::Relaxed);
-        let wr_pos = self.inspector.write_pos.load(Ordering::Relaxed);
-        let buf = guard.as_slice();
-        let buf_len = buf.len();
-        let read_start = (re_pos + (start_pos - gpos)) % buf_len;
-        if (read_start + req_cnt) < buf_len {
-            Ok(SampleRange::Adjacent(buf[read_start..read_start + req_cnt].as_ptr(), req_cnt))
-        } else {
-            let d = buf_len - read_start;
-            data[..d].copy_from_slice(&buf[read_start..]);
-            data[d..req_cnt].copy_from_slice(&buf[..(req_cnt - d)]);
-            Ok(SampleRange::NonAdjacent(req_cnt))
+        cons.commit_read(4000).unwrap();
+        // a restarted source goes backwards, and the marks before the consumer go
+        prod.write_at(&[0i16; 1600], ms(0)).unwrap();
+        let timeline = cons.peek(4000, 4000).unwrap().timeline();
+        assert_eq!(timeline.marks(), &[
+            TimelineMark { pos: 3200, pts: ms(5700) },
+            TimelineMark { pos: 6400, pts: ms(0) },
+        ]);
+        assert_eq!(timeline.source_time(8000), Some(ms(100)));
+    }
+
+    #[test]
+    fn wrapped_view_has_two_slices() {
+        let rb = SpscRb::new(8);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 6]).unwrap();
+        cons.commit_read(6).unwrap();
+        prod.write_ext_blocking(&[16384i16; 4]).unwrap();
+        let view = cons.peek(6, 4).unwrap();
+        assert_eq!(view.as_slices(), (&[0.5f32; 2][..], &[0.5f32; 2][..]));
+        let mut scratch = Vec::new();
+        assert_eq!(view.contiguous(&mut scratch), &[0.5; 4]);
+    }
+
+    #[test]
+    fn reads_by_time() {
+        let rb = SpscRb::new(16000);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        let input: Vec<i16> = (0..8000).map(|i| i as i16).collect();
+        prod.write_ext_blocking(&input).unwrap();
+        {
+            let view = cons.read_ms(100..250).unwrap();
+            assert_eq!((view.pos(), view.len()), (1600, 2400));
+            assert_eq!(view.as_slices().0[0], 1600.0 / 32768.0);
         }
+        assert_eq!(cons.sample_at(Duration::from_micros(62_500)).unwrap(), 1000.0 / 32768.0);
+        // not written yet
+        assert!(matches!(cons.read_ms(400..600), Err(RbError::Again)));
+        assert!(matches!(cons.sample_at(Duration::from_millis(500)), Err(RbError::Again)));
     }
 
-    fn commit_read(&self, read_end: usize) {
-        let guard = self.buf.lock().unwrap();
-        let buf_len = guard.as_slice().len();
-        let gpos = self.inspector.gpos.load(Ordering::Relaxed);
-        let cnt = read_end - gpos;
-        let available_cnt = self.inspector.count();
-        if available_cnt < cnt {
-            panic!("can't commit data, not enough")
+    #[test]
+    fn bad_time_ranges_are_errors() {
+        let rb = SpscRb::new(1600);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 1600]).unwrap();
+        assert!(matches!(cons.read_ms(Range { start: 50, end: 20 }), Err(RbError::InvalidRange { .. })));
+        // longer than the 100 ms the buffer holds
+        assert!(matches!(cons.read_ms(0..200), Err(RbError::InvalidRange { .. })));
+        cons.commit_read(800).unwrap();
+        assert!(matches!(cons.read_ms(20..60), Err(RbError::Released { pos: 320, read: 800 })));
+        drop(prod);
+        assert!(matches!(cons.sample_at(Duration::from_millis(100)), Err(RbError::EOF)));
+    }
+
+    #[test]
+    fn committing_unwritten_samples_is_an_error() {
+        let rb = SpscRb::new(16);
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 4]).unwrap();
+        assert!(matches!(cons.commit_read(5), Err(RbError::Unwritten { pos: 5, written: 4 })));
+        cons.commit_read(4).unwrap();
+        assert!(matches!(cons.peek(0, 1), Err(RbError::Released { pos: 0, read: 4 })));
+    }
+
+    #[test]
+    fn clock_sets_the_rate() {
+        let rb = SpscRb::with_clock(48000, SampleClock::new(48000));
+        let (prod, mut cons) = (rb.producer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 4800]).unwrap();
+        let view = cons.read_time(Duration::from_millis(50)..Duration::from_millis(100)).unwrap();
+        assert_eq!((view.pos(), view.len()), (2400, 2400));
+    }
+
+    #[test]
+    fn every_consumer_reads_everything() {
+        let rb = SpmcRb::new(1000, 3);
+        let prod = rb.producer();
+        let input: Vec<i16> = (0..100_000).map(|i| (i % 65536 - 32768) as i16).collect();
+        let expected: Vec<f32> = input.iter().map(|x| *x as f32 / 32768.0).collect();
+        let readers: Vec<_> = [160, 777, 1000].into_iter()
+            .map(|block| {
+                let mut cons = rb.consumer();
+                thread::spawn(move || read_all(&mut cons, block))
+            })
+            .collect();
+        for block in input.chunks(333) {
+            prod.write_ext_blocking(block).unwrap();
+        }
+        drop(prod);
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn slowest_consumer_holds_the_space() {
+        let rb = SpmcRb::new(10, 2);
+        let (prod, mut fast, mut slow) = (rb.producer(), rb.consumer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 10]).unwrap();
+        fast.commit_read(10).unwrap();
+        assert!(matches!(prod.write_blocking_timeout(&[0; 4], Duration::ZERO), Err(RbError::TimedOut)));
+        slow.commit_read(4).unwrap();
+        assert_eq!(prod.write_blocking_timeout(&[0; 8], Duration::ZERO).unwrap(), Some(4));
+        // each consumer peeks from its own cursor
+        assert!(matches!(fast.peek(4, 1), Err(RbError::Released { pos: 4, read: 10 })));
+        assert_eq!(slow.peek(4, 10).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn dropped_consumers_release_the_producer() {
+        let rb = SpmcRb::new(10, 2);
+        let (prod, mut first, second) = (rb.producer(), rb.consumer(), rb.consumer());
+        prod.write_ext_blocking(&[0; 10]).unwrap();
+        first.commit_read(10).unwrap();
+        drop(second);
+        assert_eq!(prod.write_blocking_timeout(&[0; 10], Duration::ZERO).unwrap(), Some(10));
+        let writer = thread::spawn(move || prod.write_blocking(&[0; 10]));
+        thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert!(matches!(writer.join().unwrap(), Err(RbError::EOF)));
+    }
+
+    fn samples(range: std::ops::Range<i16>) -> Vec<i16> {
+        range.map(|i| i * 100).collect()
+    }
+
+    fn first_sample(view: &ReadView) -> f32 {
+        view.as_slices().0[0] * 32768.0 / 100.0
+    }
+
+    #[test]
+    fn drop_newest_and_error_policies() {
+        let rb = SpscRb::new(10);
+        let prod = rb.producer().with_overflow(OverflowPolicy::DropNewest);
+        let _cons = rb.consumer();
+        assert_eq!(prod.write(&[0; 16]).unwrap(), 10);
+        assert_eq!((prod.dropped_newest(), prod.dropped_oldest()), (6, 0));
+
+        let rb = SpscRb::new(10);
+        let (prod, _cons) = (rb.producer().with_overflow(OverflowPolicy::Error), rb.consumer());
+        assert_eq!(prod.write(&[0; 6]).unwrap(), 6);
+        assert!(matches!(prod.write(&[0; 6]), Err(RbError::Full)));
+        assert_eq!((rb.count(), rb.dropped_newest()), (6, 0));
+    }
+
+    #[test]
+    fn drop_oldest_moves_the_consumer_past_a_gap() {
+        let rb = SpscRb::new(10);
+        let (prod, mut cons) = (rb.producer().with_overflow(OverflowPolicy::DropOldest), rb.consumer());
+        prod.write(&samples(0..10)).unwrap();
+        cons.commit_read(2).unwrap();
+        assert_eq!(prod.write(&samples(10..16)).unwrap(), 6);
+        assert_eq!(cons.dropped_oldest(), 4);
+        assert!(matches!(cons.peek(2, 10), Err(RbError::Dropped { pos: 2, resume: 6 })));
+        let view = cons.peek(6, 10).unwrap();
+        assert_eq!(first_sample(&view), 6.0);
+        assert_eq!(view.commit(), 16);
+        // of a write longer than the buffer, the newest samples are kept
+        assert_eq!(prod.write(&samples(16..30)).unwrap(), 10);
+        assert_eq!(cons.dropped_oldest(), 4 + 4);
+        assert_eq!(first_sample(&cons.peek(16, 10).unwrap()), 20.0);
+    }
+
+    #[test]
+    fn drop_oldest_spares_a_view() {
+        let rb = SpscRb::new(10);
+        let (prod, mut cons) = (rb.producer().with_overflow(OverflowPolicy::DropOldest), rb.consumer());
+        prod.write(&samples(0..10)).unwrap();
+        {
+            let view = cons.peek(3, 5).unwrap();
+            // only the samples before the view can go
+            assert_eq!(prod.write(&samples(10..15)).unwrap(), 3);
+            assert_eq!((prod.dropped_oldest(), prod.dropped_newest()), (3, 2));
+            assert_eq!(first_sample(&view), 3.0);
         }
-        self.inspector.gpos.store(read_end, Ordering::Relaxed);
-        let re_pos = self.inspector.read_pos.load(Ordering::Relaxed);
-        self.inspector.read_pos.store((re_pos + cnt) % buf_len, Ordering::Relaxed);
-        self.slots_free.notify_one();
+        assert_eq!(prod.write(&samples(15..17)).unwrap(), 2);
+        let view = cons.peek(5, 10).unwrap();
+        assert_eq!((first_sample(&view), view.len()), (5.0, 10));
     }
 
+    #[test]
+    fn drop_oldest_never_tears_a_view() {
+        let rb = SpscRb::new(64);
+        let (prod, mut cons) = (rb.producer().with_overflow(OverflowPolicy::DropOldest), rb.consumer());
+        let writer = thread::spawn(move || {
+            for block in (0..32000).collect::<Vec<i16>>().chunks(7) {
+                prod.write(block).unwrap();
+            }
+        });
+        // overwritten samples would break the ramp
+        let (mut pos, mut last) = (0, -1.0);
+        loop {
+            let view = match cons.peek_blocking(pos, 16) {
+                Ok(view) => view,
+                Err(RbError::Dropped { resume, .. }) => {
+                    pos = resume;
+                    continue;
+                }
+                Err(RbError::EOF) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let (first, second) = view.as_slices();
+            for sample in first.iter().chain(second) {
+                assert!(*sample > last, "{} after {}", sample, last);
+                last = *sample;
+            }
+            pos = view.commit();
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "already has a consumer")]
+    fn second_consumer_panics() {
+        let rb = SpscRb::new(8);
+        let _first = rb.consumer();
+        let _second = rb.consumer();
+    }
 }
@@ -0,0 +1,301 @@
+use std::f64::consts::PI;
+
+/// Trade-off between resampling quality and CPU time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    Fast,
+    #[default]
+    Normal,
+    High,
+}
+
+struct FilterParams {
+    // zero crossings of the sinc on each side, at the output rate when downsampling
+    zero_crossings: usize,
+    // table resolution between two input samples
+    phases: usize,
+    kaiser_beta: f64,
+    // passband edge relative to the lower of the two Nyquist frequencies
+    rolloff: f64,
+}
+
+impl ResampleQuality {
+    fn params(&self) -> FilterParams {
+        match self {
+            ResampleQuality::Fast => FilterParams { zero_crossings: 8, phases: 64, kaiser_beta: 6.0, rolloff: 0.90 },
+            ResampleQuality::Normal => FilterParams { zero_crossings: 16, phases: 128, kaiser_beta: 8.6, rolloff: 0.92 },
+            ResampleQuality::High => FilterParams { zero_crossings: 32, phases: 256, kaiser_beta: 10.0, rolloff: 0.95 },
+        }
+    }
+}
+
+/// Which resampler converts decoded audio to 16 kHz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResamplerBackend {
+    /// libswresample, with its default filter. Raw PCM and the built-in WAV reader
+    /// don't go through ffmpeg and use `Sinc(ResampleQuality::Normal)` instead.
+    Ffmpeg,
+    /// The built-in `SincResampler`.
+    Sinc(ResampleQuality),
+}
+
+impl Default for ResamplerBackend {
+    fn default() -> Self {
+        if cfg!(feature = "ffmpeg") {
+            ResamplerBackend::Ffmpeg
+        } else {
+            ResamplerBackend::Sinc(ResampleQuality::default())
+        }
+    }
+}
+
+impl ResamplerBackend {
+    /// Quality of the sinc resampler for decoders that don't have the ffmpeg one.
+    pub fn sinc_quality(&self) -> ResampleQuality {
+        match self {
+            ResamplerBackend::Sinc(quality) => *quality,
+            ResamplerBackend::Ffmpeg => ResampleQuality::default(),
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= (half / k as f64) * (half / k as f64);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Streaming band-limited resampler for arbitrary rate ratios.
+///
+/// Interpolates a Kaiser windowed sinc, tabulated at `phases` points per input
+/// sample, so the ratio doesn't have to be rational with small terms.
+/// State is kept between calls to `process`, a stream can be fed in blocks of any size.
+pub struct SincResampler {
+    // reduced ratio: each output sample advances the input by in_rate / out_rate
+    in_rate: u64,
+    out_rate: u64,
+    // kernel half width in input samples
+    half_width: usize,
+    phases: usize,
+    table: Vec<f32>,
+    history: Vec<f32>,
+    // absolute input index of history[0]
+    history_start: i64,
+    input_len: u64,
+    output_len: u64,
+}
+
+impl SincResampler {
+    pub fn new(in_rate: u32, out_rate: u32, quality: ResampleQuality) -> Self {
+        assert!(in_rate > 0 && out_rate > 0, "sample rates must be positive");
+        let divisor = gcd(in_rate as u64, out_rate as u64);
+        let params = quality.params();
+        let cutoff = params.rolloff * f64::min(1.0, out_rate as f64 / in_rate as f64);
+        let half_width = (params.zero_crossings as f64 / cutoff).ceil() as usize;
+
+        let len = 2 * half_width * params.phases + 1;
+        let beta_norm = bessel_i0(params.kaiser_beta);
+        let table = (0..len)
+            .map(|j| {
+                let t = j as f64 / params.phases as f64 - half_width as f64;
+                let x = cutoff * t;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                let r = t / half_width as f64;
+                let window = bessel_i0(params.kaiser_beta * (1.0 - r * r).max(0.0).sqrt()) / beta_norm;
+                (cutoff * sinc * window) as f32
+            })
+            .collect();
+
+        Self {
+            in_rate: in_rate as u64 / divisor,
+            out_rate: out_rate as u64 / divisor,
+            half_width,
+            phases: params.phases,
+            table,
+            // silence before the first sample, so the first output is centered on it
+            history: vec![0.0; half_width],
+            history_start: -(half_width as i64),
+            input_len: 0,
+            output_len: 0,
+        }
+    }
+
+    // integer and fractional input position of output sample `n`
+    fn position(&self, n: u64) -> (i64, f64) {
+        let num = n * self.in_rate;
+        ((num / self.out_rate) as i64, (num % self.out_rate) as f64 / self.out_rate as f64)
+    }
+
+    fn compute(&self, index: i64, frac: f64) -> f32 {
+        let hw = self.half_width as i64;
+        let fp = frac * self.phases as f64;
+        let jf = fp.floor() as usize;
+        let a = (fp - jf as f64) as f32;
+        let first = (index - hw + 1 - self.history_start) as usize;
+        let samples = &self.history[first..first + 2 * self.half_width];
+        let mut acc = 0.0f32;
+        for (k, sample) in samples.iter().enumerate() {
+            // distance of the tap from the output position is (k + 1 - hw) - frac,
+            // in table units that's m - a
+            let m = (k + 1) * self.phases - jf;
+            let h = self.table[m] * (1.0 - a) + self.table[m - 1] * a;
+            acc += sample * h;
+        }
+        acc
+    }
+
+    fn drain_into(&mut self, out: &mut Vec<f32>, until: i64) {
+        let hw = self.half_width as i64;
+        loop {
+            let (index, frac) = self.position(self.output_len);
+            if index >= until || index + hw >= self.history_start + self.history.len() as i64 {
+                break;
+            }
+            out.push(self.compute(index, frac));
+            self.output_len += 1;
+        }
+        // drop history nothing will look at anymore
+        let (index, _) = self.position(self.output_len);
+        let keep_from = index - hw + 1;
+        let drop = (keep_from - self.history_start).clamp(0, self.history.len() as i64) as usize;
+        if drop > 0 {
+            self.history.drain(..drop);
+            self.history_start += drop as i64;
+        }
+    }
+
+    /// Resamples `input`, appending what can be computed so far to `out`.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        if self.in_rate == self.out_rate {
+            out.extend_from_slice(input);
+            return;
+        }
+        self.history.extend_from_slice(input);
+        self.input_len += input.len() as u64;
+        self.drain_into(out, i64::MAX);
+    }
+
+    /// Emits the remaining output for the end of the stream, as if it was followed by silence.
+    /// The resampler is reset afterwards.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        if self.in_rate == self.out_rate {
+            return;
+        }
+        self.history.resize(self.history.len() + self.half_width, 0.0);
+        let end = self.input_len as i64;
+        self.drain_into(out, end);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.history.resize(self.half_width, 0.0);
+        self.history_start = -(self.half_width as i64);
+        self.input_len = 0;
+        self.output_len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32 * 0.5).collect()
+    }
+
+    fn resample_all(input: &[f32], in_rate: u32, out_rate: u32, quality: ResampleQuality, block: usize) -> Vec<f32> {
+        let mut resampler = SincResampler::new(in_rate, out_rate, quality);
+        let mut out = Vec::new();
+        for chunk in input.chunks(block) {
+            resampler.process(chunk, &mut out);
+        }
+        resampler.flush(&mut out);
+        out
+    }
+
+    fn rms(data: &[f32]) -> f64 {
+        (data.iter().map(|x| (*x as f64) * (*x as f64)).sum::<f64>() / data.len() as f64).sqrt()
+    }
+
+    fn snr_db(output: &[f32], freq: f64, rate: u32, skip: usize) -> f64 {
+        let expected = sine(freq, rate, output.len());
+        let signal = &expected[skip..output.len() - skip];
+        let noise: Vec<f32> = output[skip..output.len() - skip].iter()
+            .zip(signal)
+            .map(|(o, e)| o - e)
+            .collect();
+        20.0 * (rms(signal) / rms(&noise)).log10()
+    }
+
+    #[test]
+    fn output_length_follows_ratio() {
+        for (in_rate, out_rate) in [(48000, 16000), (44100, 16000), (8000, 16000), (22050, 16000)] {
+            let input = sine(440.0, in_rate, in_rate as usize);
+            let out = resample_all(&input, in_rate, out_rate, ResampleQuality::Normal, 1000);
+            assert_eq!(out.len(), out_rate as usize, "{} -> {}", in_rate, out_rate);
+        }
+    }
+
+    #[test]
+    fn same_rate_is_identity() {
+        let input = sine(1000.0, 16000, 4000);
+        let out = resample_all(&input, 16000, 16000, ResampleQuality::Normal, 333);
+        assert_eq!(input, out);
+    }
+
+    #[test]
+    fn downsampling_snr() {
+        for (in_rate, quality, min_snr) in [
+            (48000, ResampleQuality::Fast, 40.0),
+            (48000, ResampleQuality::Normal, 60.0),
+            (44100, ResampleQuality::Normal, 60.0),
+            (44100, ResampleQuality::High, 70.0),
+        ] {
+            let input = sine(1000.0, in_rate, in_rate as usize);
+            let out = resample_all(&input, in_rate, 16000, quality, 4096);
+            let snr = snr_db(&out, 1000.0, 16000, 200);
+            assert!(snr > min_snr, "{} Hz {:?}: snr {:.1} dB", in_rate, quality, snr);
+        }
+    }
+
+    #[test]
+    fn upsampling_snr() {
+        let input = sine(1000.0, 8000, 8000);
+        let out = resample_all(&input, 8000, 16000, ResampleQuality::Normal, 512);
+        let snr = snr_db(&out, 1000.0, 16000, 200);
+        assert!(snr > 60.0, "snr {:.1} dB", snr);
+    }
+
+    #[test]
+    fn rejects_aliasing() {
+        // above the 8 kHz output Nyquist frequency, must not fold back into the band
+        for freq in [9000.0, 12000.0, 20000.0] {
+            let input = sine(freq, 48000, 48000);
+            let out = resample_all(&input, 48000, 16000, ResampleQuality::Normal, 4096);
+            let level = 20.0 * (rms(&out[200..out.len() - 200]) / rms(&input)).log10();
+            assert!(level < -60.0, "{} Hz aliased at {:.1} dB", freq, level);
+        }
+    }
+
+    #[test]
+    fn block_size_does_not_matter() {
+        let input = sine(700.0, 44100, 20000);
+        let whole = resample_all(&input, 44100, 16000, ResampleQuality::Normal, input.len());
+        let blocks = resample_all(&input, 44100, 16000, ResampleQuality::Normal, 37);
+        assert_eq!(whole, blocks);
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+/// What to do when whisper fails on a chunk of audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailurePolicy {
+    /// Stop the session and return `WhisperError::Inference`.
+    #[default]
+    Abort,
+    /// Leave the chunk out of the transcript and go on with the next one.
+    SkipChunk,
+    /// Run the chunk again with the cheaper fallback decoding parameters,
+    /// skip it if that fails too.
+    RetryWithFallbackParams,
+}
+
+/// A chunk of audio missing from the transcript because inference failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedChunk {
+    /// Index of the transcribed stream, see `ChannelPolicy`.
+    pub channel: usize,
+    pub start: Duration,
+    pub end: Duration,
+    /// Return code of the last whisper_full run on the chunk.
+    pub code: i32,
+}
+
+/// A segment of text emitted by whisper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Index of the transcribed stream, always 0 unless channels are transcribed separately.
+    pub channel: usize,
+    /// Name given to the channel in `SessionConfig::speakers`.
+    pub speaker: Option<String>,
+    /// Time range in the input. Only chunk granular unless channels are transcribed separately.
+    pub start: Duration,
+    pub end: Duration,
+    pub text: String,
+}
+
+impl Segment {
+    /// The speaker name, or `channel <n>` if the channel has none.
+    pub fn label(&self) -> String {
+        match &self.speaker {
+            Some(speaker) => speaker.clone(),
+            None => format!("channel {}", self.channel),
+        }
+    }
+}
+
+fn format_timestamp(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+/// Result of a transcription session.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    /// Texts of `segments`, in the same order.
+    pub text: Vec<String>,
+    /// The non-empty segments with their stream and time, ordered by start time.
+    pub segments: Vec<Segment>,
+    pub skipped_chunks: Vec<SkippedChunk>,
+}
+
+impl Transcript {
+    /// Returns true if part of the audio is missing from the transcript.
+    pub fn has_gaps(&self) -> bool {
+        !self.skipped_chunks.is_empty()
+    }
+
+    /// Formats the segments as `[start --> end] speaker: text` lines, a diarized
+    /// transcript when each speaker was recorded on their own channel.
+    pub fn labeled_lines(&self) -> Vec<String> {
+        self.segments.iter()
+            .map(|segment| format!("[{} --> {}] {}: {}",
+                                   format_timestamp(segment.start), format_timestamp(segment.end),
+                                   segment.label(), segment.text.trim()))
+            .collect()
+    }
+}
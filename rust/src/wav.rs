@@ -0,0 +1,253 @@
+use std::io::{self, Read};
+
+use log::{debug, info};
+use crate::config::SessionConfig;
+use crate::errors::WhisperError;
+use crate::channels::StreamOpener;
+use crate::pcm::{process_pcm, PcmFormat, PcmSpec};
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_ALAW: u16 = 0x0006;
+const WAVE_FORMAT_MULAW: u16 = 0x0007;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+const MAX_FMT_SIZE: u32 = 1024;
+// streaming writers put this in the data chunk size when the length is unknown
+const UNKNOWN_DATA_SIZE: u32 = 0xffff_ffff;
+
+/// Layout of a WAV file's `data` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavHeader {
+    pub spec: PcmSpec,
+    /// Size of the `data` chunk in bytes, `None` if the writer didn't know it and
+    /// the data goes on to the end of the file.
+    pub data_size: Option<u32>,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn invalid(msg: &str) -> WhisperError {
+    WhisperError::Decode(format!("invalid wav file: {}", msg))
+}
+
+fn parse_fmt(chunk: &[u8]) -> Result<PcmSpec, WhisperError> {
+    if chunk.len() < 16 {
+        return Err(invalid("fmt chunk too short"));
+    }
+    let mut format_tag = read_u16(chunk, 0);
+    let channels = read_u16(chunk, 2);
+    let sample_rate = read_u32(chunk, 4);
+    let bits_per_sample = read_u16(chunk, 14);
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        // cbSize, wValidBitsPerSample, dwChannelMask, then the SubFormat GUID
+        // whose first two bytes are the actual format tag
+        if chunk.len() < 40 {
+            return Err(invalid("extensible fmt chunk too short"));
+        }
+        format_tag = read_u16(chunk, 24);
+    }
+    let format = match (format_tag, bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) => PcmFormat::U8,
+        (WAVE_FORMAT_PCM, 16) => PcmFormat::S16Le,
+        (WAVE_FORMAT_PCM, 24) => PcmFormat::S24Le,
+        (WAVE_FORMAT_PCM, 32) => PcmFormat::S32Le,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => PcmFormat::F32Le,
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => PcmFormat::F64Le,
+        (WAVE_FORMAT_ALAW, 8) => PcmFormat::ALaw,
+        (WAVE_FORMAT_MULAW, 8) => PcmFormat::MuLaw,
+        _ => {
+            return Err(WhisperError::Decode(format!(
+                "unsupported wav format 0x{:04x} with {} bits per sample", format_tag, bits_per_sample)));
+        }
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(invalid("no channels or zero sample rate"));
+    }
+    Ok(PcmSpec::new(format, sample_rate, channels))
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> Result<(), WhisperError> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(invalid("truncated chunk"));
+    }
+    Ok(())
+}
+
+/// Reads the RIFF header up to the start of the `data` chunk.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<WavHeader, WhisperError> {
+    let mut riff = [0u8; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid("missing RIFF/WAVE header"));
+    }
+    let mut spec = None;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        reader.read_exact(&mut chunk_header)?;
+        let chunk_id = &chunk_header[0..4];
+        let chunk_size = read_u32(&chunk_header, 4);
+        match chunk_id {
+            b"fmt " => {
+                if chunk_size > MAX_FMT_SIZE {
+                    return Err(invalid("oversized fmt chunk"));
+                }
+                let mut chunk = vec![0u8; chunk_size as usize];
+                reader.read_exact(&mut chunk)?;
+                if chunk_size % 2 == 1 {
+                    skip(reader, 1)?;
+                }
+                spec = Some(parse_fmt(&chunk)?);
+            }
+            b"data" => {
+                let spec = spec.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                // an empty data chunk is an empty file, not one of unknown length
+                let data_size = (chunk_size != UNKNOWN_DATA_SIZE).then_some(chunk_size);
+                return Ok(WavHeader { spec, data_size });
+            }
+            _ => {
+                debug!("skipping wav chunk {:?} of {} bytes", String::from_utf8_lossy(chunk_id), chunk_size);
+                // chunks are padded to an even size
+                skip(reader, chunk_size as u64 + (chunk_size % 2) as u64)?;
+            }
+        }
+    }
+}
+
+/// Feeds a WAV file into the ring buffers, split and resampled like raw PCM.
+/// This is the decoder used when the crate is built without ffmpeg.
+#[cfg_attr(feature = "ffmpeg", allow(dead_code))]
+pub fn process_wav<R: Read>(mut reader: R, opener: StreamOpener, config: &SessionConfig) -> Result<(), WhisperError> {
+    let header = read_header(&mut reader)?;
+    info!("wav: {:?}", header);
+    match header.data_size {
+        Some(data_size) => process_pcm(reader.take(data_size as u64), header.spec, opener, config),
+        None => process_pcm(reader, header.spec, opener, config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+    use super::*;
+
+    const JFK: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/samples/jfk.wav");
+
+    fn fmt(format_tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&format_tag.to_le_bytes());
+        chunk.extend_from_slice(&channels.to_le_bytes());
+        chunk.extend_from_slice(&sample_rate.to_le_bytes());
+        chunk.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        chunk.extend_from_slice(&block_align.to_le_bytes());
+        chunk.extend_from_slice(&bits.to_le_bytes());
+        chunk
+    }
+
+    fn extensible(sub_format: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let mut chunk = fmt(WAVE_FORMAT_EXTENSIBLE, channels, sample_rate, bits);
+        chunk.extend_from_slice(&22u16.to_le_bytes());
+        chunk.extend_from_slice(&bits.to_le_bytes());
+        chunk.extend_from_slice(&0x3u32.to_le_bytes());
+        // KSDATAFORMAT_SUBTYPE_PCM / _IEEE_FLOAT differ in the first two bytes
+        chunk.extend_from_slice(&sub_format.to_le_bytes());
+        chunk.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71]);
+        chunk
+    }
+
+    fn wav(chunks: &[(&[u8; 4], &[u8])], data_size: u32, data: &[u8]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, chunk) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&data_size.to_le_bytes());
+        body.extend_from_slice(data);
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend(body);
+        file
+    }
+
+    fn header(chunks: &[(&[u8; 4], &[u8])]) -> Result<WavHeader, WhisperError> {
+        read_header(&mut Cursor::new(wav(chunks, 6, &[0; 6])))
+    }
+
+    #[test]
+    fn jfk_header() {
+        let header = read_header(&mut std::fs::File::open(JFK).unwrap()).unwrap();
+        assert_eq!(header, WavHeader { spec: PcmSpec::new(PcmFormat::S16Le, 16000, 1), data_size: Some(352_000) });
+    }
+
+    #[test]
+    fn jfk_samples() {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        process_wav(std::fs::File::open(JFK).unwrap(), StreamOpener::new(16000 * 12, opened_tx),
+                    &SessionConfig::default()).unwrap();
+        let streams = opened_rx.recv().unwrap().drain();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].len(), 176_000);
+        assert!(streams[0].iter().any(|x| x.abs() > 0.1));
+    }
+
+    #[test]
+    fn formats() {
+        for (chunk, expected) in [
+            (fmt(WAVE_FORMAT_PCM, 1, 16000, 16), PcmSpec::new(PcmFormat::S16Le, 16000, 1)),
+            (fmt(WAVE_FORMAT_PCM, 2, 44100, 24), PcmSpec::new(PcmFormat::S24Le, 44100, 2)),
+            (fmt(WAVE_FORMAT_IEEE_FLOAT, 1, 48000, 32), PcmSpec::new(PcmFormat::F32Le, 48000, 1)),
+            (extensible(WAVE_FORMAT_PCM, 2, 48000, 24), PcmSpec::new(PcmFormat::S24Le, 48000, 2)),
+            (extensible(WAVE_FORMAT_IEEE_FLOAT, 6, 48000, 32), PcmSpec::new(PcmFormat::F32Le, 48000, 6)),
+        ] {
+            assert_eq!(header(&[(b"fmt ", &chunk)]).unwrap().spec, expected);
+        }
+        assert!(header(&[(b"fmt ", &fmt(0x0055, 1, 16000, 16))]).is_err());
+        // extensible without its extension
+        assert!(header(&[(b"fmt ", &fmt(WAVE_FORMAT_EXTENSIBLE, 1, 16000, 16))]).is_err());
+        assert!(header(&[]).is_err());
+    }
+
+    #[test]
+    fn chunks_before_data_are_skipped() {
+        let chunk = fmt(WAVE_FORMAT_PCM, 1, 16000, 16);
+        // odd sized, followed by a pad byte
+        let header = header(&[(b"LIST", b"abc"), (b"fmt ", &chunk), (b"fact", &[1, 0, 0, 0]), (b"junk", b"x")]).unwrap();
+        assert_eq!(header, WavHeader { spec: PcmSpec::new(PcmFormat::S16Le, 16000, 1), data_size: Some(6) });
+    }
+
+    fn samples(file: Vec<u8>) -> Vec<f32> {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        process_wav(Cursor::new(file), StreamOpener::new(16000, opened_tx), &SessionConfig::default()).unwrap();
+        opened_rx.recv().unwrap().drain().remove(0)
+    }
+
+    #[test]
+    fn data_size() {
+        let chunk = fmt(WAVE_FORMAT_PCM, 1, 16000, 16);
+        let data: Vec<u8> = [0x4000i16, -0x4000, 0x2000].iter().flat_map(|x| x.to_le_bytes()).collect();
+        // trailing bytes after the data chunk aren't audio
+        let mut file = wav(&[(b"fmt ", &chunk)], 4, &data);
+        assert_eq!(samples(file.clone()), vec![0.5, -0.5]);
+        // a streaming writer's placeholder, the data goes on to the end
+        file[40..44].copy_from_slice(&UNKNOWN_DATA_SIZE.to_le_bytes());
+        assert_eq!(read_header(&mut Cursor::new(&file)).unwrap().data_size, None);
+        assert_eq!(samples(file), vec![0.5, -0.5, 0.25]);
+        let empty = wav(&[(b"fmt ", &chunk)], 0, &data);
+        assert_eq!(read_header(&mut Cursor::new(&empty)).unwrap().data_size, Some(0));
+        assert_eq!(samples(empty), Vec::<f32>::new());
+    }
+}
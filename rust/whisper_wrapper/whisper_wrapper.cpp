@@ -2,21 +2,64 @@
 // Created by jason on 5/7/24.
 //
 
+#include <algorithm>
+#include <cstdarg>
+#include <cstring>
+#include <stdexcept>
 #include <thread>
 #include "whisper_wrapper.h"
 #include "whispercpp/rust/src/lib.rs.h"
 
 namespace WhisperRust {
 
+    static void forward_log(enum ggml_log_level level, const char * text) {
+        WhisperRust::whisper_log(static_cast<int32_t>(level),
+                                 rust::Slice<const uint8_t>(reinterpret_cast<const uint8_t *>(text), strlen(text)));
+    }
+
+    static void whisper_log_callback(enum ggml_log_level level, const char * text, void * /*user_data*/) {
+        forward_log(level, text);
+    }
+
+    // printf-style logging from the wrapper itself, ends up in the same Rust logger as whisper.cpp
+    static void wrapper_log(enum ggml_log_level level, const char * format, ...) {
+        char buffer[1024];
+        va_list args;
+        va_start(args, format);
+        int len = vsnprintf(buffer, sizeof(buffer) - 1, format, args);
+        va_end(args);
+        if (len < 0) {
+            return;
+        }
+        len = std::min(len, (int) sizeof(buffer) - 2);
+        buffer[len] = '\n';
+        buffer[len + 1] = '\0';
+        forward_log(level, buffer);
+    }
+
+    void install_whisper_log_callback() {
+        whisper_log_set(whisper_log_callback, nullptr);
+    }
+
     WhisperWrapper::WhisperWrapper(const std::string& model_path) {
         struct whisper_context_params cparams = whisper_context_default_params();
         cparams.use_gpu = true;
 
-        whisper_ctx_ = whisper_init_from_file_with_params(model_path.c_str(), cparams);
-
+        whisper_ctx_ = whisper_init_from_file_with_params_no_state(model_path.c_str(), cparams);
+        if (!whisper_ctx_) {
+            throw std::runtime_error("failed to initialize whisper context from " + model_path);
+        }
+        whisper_state_ = whisper_init_state(whisper_ctx_);
+        if (!whisper_state_) {
+            whisper_free(whisper_ctx_);
+            throw std::runtime_error("failed to initialize whisper state for " + model_path);
+        }
     }
 
     WhisperWrapper::~WhisperWrapper() {
+        if (whisper_state_) {
+            whisper_free_state(whisper_state_);
+        }
         if (whisper_ctx_) {
             whisper_free(whisper_ctx_);
         }
@@ -28,40 +71,41 @@ namespace WhisperRust {
     };
 
     void whisper_print_progress_callback(struct whisper_context * /*ctx*/, struct whisper_state * /*state*/, int progress, void * user_data) {
-        int progress_prev = ((print_user_data*) user_data)->progress;
-        if (progress >= progress_prev + 10) {
-            ((print_user_data*) user_data)->progress += 10;
-            fprintf(stderr, "%s: progress = %3d%%\n", __func__, progress);
+        if (progress != ((print_user_data*) user_data)->progress) {
+            ((print_user_data*) user_data)->progress = progress;
+            WhisperRust::send_progress(((print_user_data*)user_data)->wrapper, progress);
         }
     }
 
-    void whisper_print_segment_callback(struct whisper_context * ctx, struct whisper_state * /*state*/, int n_new, void * user_data) {
-        const int n_segments = whisper_full_n_segments(ctx);
-        printf("new segments: %d\n", n_segments);
-
-        int64_t t0 = 0;
-        int64_t t1 = 0;
+    void whisper_print_segment_callback(struct whisper_context * /*ctx*/, struct whisper_state * state, int n_new, void * user_data) {
+        const int n_segments = whisper_full_n_segments_from_state(state);
+        wrapper_log(GGML_LOG_LEVEL_DEBUG, "new segments: %d", n_segments);
+        WhisperRust::send_new_segments(((print_user_data*)user_data)->wrapper, n_new, n_segments);
 
         // print the last n_new segments
         const int s0 = n_segments - n_new;
 
         if (s0 == 0) {
-            printf("\n");
-            WhisperRust::send_text(((print_user_data*)user_data)->wrapper, std::string("\n"));
+            const int64_t t0 = whisper_full_get_segment_t0_from_state(state, s0);
+            WhisperRust::send_text(((print_user_data*)user_data)->wrapper, std::string("\n"), t0, t0);
         }
 
         for (int i = s0; i < n_segments; i++) {
-            //printf("[%s --> %s]  ", to_timestamp(t0).c_str(), to_timestamp(t1).c_str());
-
-            const char * text = whisper_full_get_segment_text(ctx, i);
-            printf("%s", text);
-            WhisperRust::send_text(((print_user_data*)user_data)->wrapper, std::string(text));
+            // in 10 ms units from the start of the buffer
+            const int64_t t0 = whisper_full_get_segment_t0_from_state(state, i);
+            const int64_t t1 = whisper_full_get_segment_t1_from_state(state, i);
 
-            fflush(stdout);
+            const char * text = whisper_full_get_segment_text_from_state(state, i);
+            wrapper_log(GGML_LOG_LEVEL_DEBUG, "segment %d [%lld --> %lld]: %s", i, (long long) t0, (long long) t1, text);
+            WhisperRust::send_text(((print_user_data*)user_data)->wrapper, std::string(text), t0, t1);
         }
     }
 
-    int32_t WhisperWrapper::infer_buffer(const SenderWrapper& sender, const float *buffer, size_t buffer_size) const {
+    int32_t WhisperWrapper::infer_mel(const SenderWrapper& sender, const float *mel, size_t n_len, size_t n_audio, const InferOptions &options) const {
+        if (whisper_set_mel_with_state(whisper_ctx_, whisper_state_, mel, n_len, n_mels()) != 0) {
+            return -1;
+        }
+
         whisper_full_params wparams = whisper_full_default_params(WHISPER_SAMPLING_GREEDY);
 
         wparams.strategy = WHISPER_SAMPLING_BEAM_SEARCH;
@@ -71,11 +115,13 @@ namespace WhisperRust {
         wparams.print_timestamps = true;
         wparams.print_special    = false;
         wparams.translate        = false;
-        wparams.language         = "auto";
+        const std::string language(options.language);
+        wparams.language         = language.c_str();
         wparams.detect_language  = false;
         wparams.n_threads        = 4;
         wparams.offset_ms        = 0;
-        wparams.duration_ms      = 0;
+        // the spectrogram is padded with silence, only transcribe the audio frames of 10 ms each
+        wparams.duration_ms      = n_audio * 10;
         wparams.debug_mode       = true;
 
         wparams.token_timestamps = false;
@@ -95,7 +141,18 @@ namespace WhisperRust {
         wparams.entropy_thold    = 2.40f;
         wparams.logprob_thold    = -1.00f;
 
-        wparams.no_timestamps    = true;
+        // segment timestamps are only needed to interleave separately transcribed channels
+        wparams.no_timestamps    = !options.timestamps;
+
+        if (options.fallback) {
+            // cheaper and more forgiving decoding for chunks that failed with the defaults
+            wparams.strategy         = WHISPER_SAMPLING_GREEDY;
+            wparams.greedy.best_of   = 1;
+            wparams.temperature_inc  = 0.2f;
+            wparams.entropy_thold    = 2.80f;
+            wparams.logprob_thold    = -1.50f;
+            wparams.initial_prompt   = nullptr;
+        }
 
         print_user_data user_data = {0, sender};
 
@@ -111,38 +168,108 @@ namespace WhisperRust {
             wparams.progress_callback_user_data = &user_data;
         }
 
-        // examples for abort mechanism
-        // in examples below, we do not abort the processing, but we could if the flag is set to true
-
         // the callback is called before every encoder run - if it returns false, the processing is aborted
-        {
-            static bool is_aborted = false; // NOTE: this should be atomic to avoid data race
+        wparams.encoder_begin_callback = [](struct whisper_context * /*ctx*/, struct whisper_state * /*state*/, void * user_data) {
+            wrapper_log(GGML_LOG_LEVEL_DEBUG, "encoder begin");
+            return !WhisperRust::is_aborted(((print_user_data*)user_data)->wrapper);
+        };
+        wparams.encoder_begin_callback_user_data = &user_data;
+
+        // the callback is called before every computation - if it returns true, the computation is aborted
+        wparams.abort_callback = [](void * user_data) {
+            return WhisperRust::is_aborted(((print_user_data*)user_data)->wrapper);
+        };
+        wparams.abort_callback_user_data = &user_data;
+
+        wrapper_log(GGML_LOG_LEVEL_INFO, "infer mel of %zu frames", n_audio);
+        // no samples, whisper transcribes the spectrogram set above
+        return whisper_full_with_state(whisper_ctx_, whisper_state_, wparams, nullptr, 0);
+    }
+
+    int32_t WhisperWrapper::n_mels() const {
+        return whisper_model_n_mels(whisper_ctx_);
+    }
 
-            wparams.encoder_begin_callback = [](struct whisper_context * /*ctx*/, struct whisper_state * /*state*/, void * user_data) {
-                printf("encoder begin \n");
-                bool is_aborted = *(bool*)user_data;
-                return !is_aborted;
-            };
-            wparams.encoder_begin_callback_user_data = &is_aborted;
+    int32_t WhisperWrapper::encode(const float *mel, size_t n_len, int32_t offset, int32_t n_threads) const {
+        if (whisper_set_mel_with_state(whisper_ctx_, whisper_state_, mel, n_len, n_mels()) != 0) {
+            return -1;
         }
+        logits_ = nullptr;
+        return whisper_encode_with_state(whisper_ctx_, whisper_state_, offset, n_threads);
+    }
 
-        // the callback is called before every computation - if it returns true, the computation is aborted
-        {
-            static bool is_aborted = false; // NOTE: this should be atomic to avoid data race
+    int32_t WhisperWrapper::decode(rust::Slice<const int32_t> tokens, int32_t n_past, int32_t n_threads) const {
+        logits_ = nullptr;
+        const int32_t ret = whisper_decode_with_state(whisper_ctx_, whisper_state_, tokens.data(), tokens.size(), n_past, n_threads);
+        if (ret == 0) {
+            logits_ = whisper_get_logits_from_state(whisper_state_) + (tokens.size() - 1) * n_vocab();
+        }
+        return ret;
+    }
+
+    rust::Slice<const float> WhisperWrapper::logits() const {
+        if (!logits_) {
+            return rust::Slice<const float>();
+        }
+        return rust::Slice<const float>(logits_, n_vocab());
+    }
+
+    rust::Vec<int32_t> WhisperWrapper::tokenize(rust::Str text) const {
+        const std::string utf8(text);
+        std::vector<whisper_token> tokens(n_text_ctx());
+        int n = whisper_tokenize(whisper_ctx_, utf8.c_str(), tokens.data(), tokens.size());
+        if (n < 0) {
+            // longer than a context, still tokenized whole
+            tokens.resize(-n);
+            n = whisper_tokenize(whisper_ctx_, utf8.c_str(), tokens.data(), tokens.size());
+        }
+        rust::Vec<int32_t> out;
+        out.reserve(n);
+        for (int i = 0; i < n; i++) {
+            out.push_back(tokens[i]);
+        }
+        return out;
+    }
 
-            wparams.abort_callback = [](void * user_data) {
-                bool is_aborted = *(bool*)user_data;
-                return is_aborted;
-            };
-            wparams.abort_callback_user_data = &is_aborted;
+    rust::Slice<const uint8_t> WhisperWrapper::token_bytes(int32_t token) const {
+        // a token can be part of a UTF-8 sequence
+        const char * text = whisper_token_to_str(whisper_ctx_, token);
+        return rust::Slice<const uint8_t>(reinterpret_cast<const uint8_t *>(text), strlen(text));
+    }
+
+    int32_t WhisperWrapper::special_token(SpecialToken token) const {
+        switch (token) {
+            case SpecialToken::EndOfText: return whisper_token_eot(whisper_ctx_);
+            case SpecialToken::StartOfTranscript: return whisper_token_sot(whisper_ctx_);
+            case SpecialToken::StartOfLm: return whisper_token_solm(whisper_ctx_);
+            case SpecialToken::Previous: return whisper_token_prev(whisper_ctx_);
+            case SpecialToken::NoSpeech: return whisper_token_nosp(whisper_ctx_);
+            case SpecialToken::NoTimestamps: return whisper_token_not(whisper_ctx_);
+            case SpecialToken::TimestampBegin: return whisper_token_beg(whisper_ctx_);
+            case SpecialToken::Translate: return whisper_token_translate(whisper_ctx_);
+            case SpecialToken::Transcribe: return whisper_token_transcribe(whisper_ctx_);
         }
+        return -1;
+    }
+
+    int32_t WhisperWrapper::lang_token(rust::Str lang) const {
+        const int lang_id = whisper_lang_id(std::string(lang).c_str());
+        if (lang_id < 0) {
+            return -1;
+        }
+        return whisper_token_lang(whisper_ctx_, lang_id);
+    }
+
+    int32_t WhisperWrapper::n_vocab() const {
+        return whisper_n_vocab(whisper_ctx_);
+    }
 
-        printf("infer buffer size %ld\n", buffer_size);
-        return whisper_full(whisper_ctx_, wparams, buffer, buffer_size);
+    int32_t WhisperWrapper::n_text_ctx() const {
+        return whisper_n_text_ctx(whisper_ctx_);
     }
 
-    int32_t WhisperWrapper::get_segment_count() const {
-        return whisper_full_n_segments(whisper_ctx_);
+    bool WhisperWrapper::is_multilingual() const {
+        return whisper_is_multilingual(whisper_ctx_) != 0;
     }
 
     std::unique_ptr<WhisperWrapper> create_whisper_wrapper(rust::Str model_path) {
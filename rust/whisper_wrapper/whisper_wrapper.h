@@ -12,19 +12,37 @@
 namespace WhisperRust {
 
     struct SenderWrapper;
+    struct InferOptions;
+    enum class SpecialToken : uint8_t;
 
     class WhisperWrapper {
     public:
         explicit WhisperWrapper(const std::string& model_path);
         ~WhisperWrapper();
 
-        int32_t infer_buffer(const SenderWrapper &sender, const float* buffer, size_t buffer_size) const;
-        int32_t get_segment_count() const;
+        int32_t infer_mel(const SenderWrapper &sender, const float* mel, size_t n_len, size_t n_audio, const InferOptions &options) const;
+        int32_t n_mels() const;
+
+        // primitives for decoding loops written in Rust, which checks their arguments
+        int32_t encode(const float* mel, size_t n_len, int32_t offset, int32_t n_threads) const;
+        int32_t decode(rust::Slice<const int32_t> tokens, int32_t n_past, int32_t n_threads) const;
+        rust::Slice<const float> logits() const;
+        rust::Vec<int32_t> tokenize(rust::Str text) const;
+        rust::Slice<const uint8_t> token_bytes(int32_t token) const;
+        int32_t special_token(SpecialToken token) const;
+        int32_t lang_token(rust::Str lang) const;
+        int32_t n_vocab() const;
+        int32_t n_text_ctx() const;
+        bool is_multilingual() const;
         int progress_ = 0;
     private:
         std::string prompt_;
+        // last row of the logits of the last decode, the others aren't computed
+        mutable const float* logits_ = nullptr;
         struct whisper_context* whisper_ctx_;
+        struct whisper_state* whisper_state_;
     };
 
     std::unique_ptr<WhisperWrapper> create_whisper_wrapper(rust::Str model_path);
+    void install_whisper_log_callback();
 }
\ No newline at end of file